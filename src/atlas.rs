@@ -1,36 +1,121 @@
 use crate::errors::*;
-use crate::map::Map;
+use crate::map::{Map, HeightGradient};
 use crate::coord::Coord;
-use crate::mapfolder::{ZipMount, map_dir};
+use crate::interpolation::{Interpolation, interpolate};
+use crate::policy::OverlapPolicy;
+use crate::survey::SurveyInfo;
+use crate::raster::{BlockSize, SampleStorage};
+use crate::cache::{RasterCache, file_stamp};
+use crate::geometry;
+use crate::spatial::MapIndex;
+use crate::indexfile;
+use crate::catalogue::{Catalogue, CatalogueEntry, CATALOGUE_FILE};
+use crate::mapfolder::{MapFolder, MAPFOLDER};
 
 use crossbeam_channel::{Sender, Receiver};
-use std::f32::consts::PI;
+use std::cell::Cell;
+use std::f64::consts::PI;
 use std::collections::HashMap;
 use std::{fs, fmt};
-use std::rc::Rc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
 use serde::{Deserialize, Serialize, Serializer, Deserializer};
 use serde::{ser::SerializeSeq, de::Visitor, de::SeqAccess};
 
 pub type MsgSender = Sender<String>;
 pub type MsgReceiver = Receiver<String>;
 
+// Number of times a lookup loads a map image again after it has been
+// evicted by other threads, before giving up with MapNotLoaded
+const MAX_RELOADS: usize = 3;
+
+// Outcome of preloading a region
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PreloadSummary {
+    // Maps overlapping the region
+    pub maps: usize,
+    // Maps loaded by the preload. The others were already loaded.
+    pub loaded: usize,
+    // Maps unloaded during the preload to stay within the memory budget
+    pub evicted: u64,
+    // Memory held by the maps of the region after the preload
+    pub bytes: usize,
+}
+
+/*
+Result of a lookup together with where it came from. With the Average
+overlap policy, the map fields describe the finest of the averaged maps.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct LookupInfo {
+    pub height: f32,
+    // Gradient (dh/dx, dh/dy)
+    pub gradient: (f32, f32),
+    pub fname: String,
+    pub zipfile: String,
+    // Column and row of the pixel containing the coordinate
+    pub pixel: (usize, usize),
+    // Coordinate of the centre of the pixel
+    pub centre: Coord,
+    pub resolution: f32,
+    pub interpolation: Interpolation,
+    // Number of maps the values are averaged over
+    pub maps: usize,
+}
+
+// Number of map images loaded, and the memory they hold
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct MemoryUsage {
+    pub maps: usize,
+    pub bytes: usize,
+    // Largest difference between a loaded sample and the value in the file
+    pub max_error: f32,
+}
+
 /* 
-For better performance, maps are hashed up on low-res coordinates. Each
-low-res coordinate points to a vector of candidate maps for the coordinate.
-Some of these maps may be outside of the actual coordinate.
+The maps are kept in a spatial index of their footprints, which finds the
+candidate maps for a coordinate, an area or a path. An atlas created from the
+map directory only knows the extents of the index files at first. The maps
+of an index file are read the first time a query touches its extent.
+
+The atlas is Send + Sync. One instance can be shared (e.g. in an Arc) between
+threads doing lookups concurrently. Map images are loaded on first use, and
+only once even if several threads need the same map at the same time.
+
+Loaded images are kept in memory until the atlas is dropped, unless a memory
+budget is set. With a budget, the least recently used images are evicted when
+the total size of the loaded images exceeds the budget. Evicted maps are
+loaded again when needed.
  */
 pub struct Atlas {
-    maps: HashMap<i32, Vec::<Rc<Map>>>,
+    // Folder the maps and index files are read from
+    folder: Arc<MapFolder>,
+    maps: RwLock<MapIndex>,
+    // Index files not read yet
+    pending: RwLock<Vec<CatalogueEntry>>,
     mockup: bool,
     tx: Option<MsgSender>,
+    interpolation: Interpolation,
+    overlap_policy: OverlapPolicy,
+    block_size: BlockSize,
+    storage: SampleStorage,
+    cache: Option<RasterCache>,
+    memory_budget: Option<usize>,
+    // Maps with images loaded through this atlas, keyed on file name
+    resident: Mutex<HashMap<String, Arc<Map>>>,
+    clock: AtomicU64,
+    evictions: AtomicU64,
 }
 
 impl Atlas {
-    pub fn new_from_directory(directory: &str, zipfile: &str,
-                              tx: Option<MsgSender>) -> Result<Self> {
-	let absdir = format!("{}{}", map_dir(), directory);
-
-	let mut maps = HashMap::new();
+    // Create an atlas of the maps in a directory of the map folder, for
+    // indexing
+    pub fn new_from_directory(folder: &Arc<MapFolder>, directory: &str,
+                              zipfile: &str, tx: Option<MsgSender>)
+                              -> Result<Self> {
+	let absdir = folder.path(directory)?;
+	let mut files = Vec::new();
 
 	for fentry in fs::read_dir(absdir)? {
             let path = fentry?.path();
@@ -38,157 +123,742 @@ impl Atlas {
 		continue;
             }
 
-	    if !(path.extension().unwrap() == "tif") {
+	    files.push(path);
+	}
+
+	Self::new_from_files(folder, directory, &files, zipfile, tx)
+    }
+
+    // Create an atlas of the maps in a zip file of the map folder, for
+    // indexing. The zip file is read through GDAL, without mounting it.
+    pub fn new_from_zip_file(folder: &Arc<MapFolder>, file: &str,
+                             tx: Option<MsgSender>) -> Result<Self> {
+	if !file.ends_with(".zip") {
+	    return Err(Error::NotZipFile(file.to_string()).into());
+	}
+
+	// Entries in the top level of the zip file, as for directories
+	let files = gdal::vsi::read_dir(folder.zip_path(file)?, false)?;
+
+	Self::new_from_files(folder, &format!("{}.dir/", file), &files, file,
+			     tx)
+    }
+
+    // Create an atlas of the tiff files among files, named with the prefix
+    // directory
+    fn new_from_files(folder: &Arc<MapFolder>, directory: &str,
+                      files: &[PathBuf], zipfile: &str,
+                      tx: Option<MsgSender>) -> Result<Self> {
+	let mut maps = Vec::new();
+
+	for path in files {
+	    if path.extension().is_none_or(|e| e != "tif") {
 		continue;
 	    }
 
 	    let fname = path.file_name().unwrap().to_str().unwrap();
 	    let dir_and_name = format!("{}{}", directory, fname);
-	    let m = Rc::new(Map::new(&dir_and_name, &zipfile, tx.as_ref())?);
-
-	    for h in m.hashes() {
-		if !maps.contains_key(&h) {
-		    maps.insert(h, Vec::new());
-		}
-		maps.get_mut(&h).unwrap().push(Rc::clone(&m));
-	    }
+	    maps.push(Arc::new(Map::new(folder, &dir_and_name, zipfile,
+					tx.as_ref())?));
 	}
 
-	Ok(Self {
-            maps: maps,
-            mockup: false,
-            tx: tx,
-        })
-    }
+	Self::common_crs(maps.iter().map(|m| (m.fname.as_str(), m.crs)))?;
 
-    pub fn new_from_zip_file(file: &str, tx: Option<MsgSender>)
-                             -> Result<Self> {
-	// Mount the zip file
-	let zm = ZipMount::new(&file);
+	let mut s = Self::new_empty(tx);
+	s.folder = Arc::clone(folder);
+	s.maps = RwLock::new(MapIndex::new(maps));
 
-	Atlas::new_from_directory(&zm.directory, file, tx)
+	Ok(s)
     }
 
     // Create a mockup atlas for testing
     pub fn new_mockup() -> Self {
-        Self {
-            maps: HashMap::new(),
-            mockup: true,
-            tx: None,
-        }
+        let mut s = Self::new_empty(None);
+        s.mockup = true;
+        s
     }
 
-    fn read_atlas(file: &str) -> Result<Self> {
-        let data = fs::read_to_string(&file).expect("Unable to read file");
-        let s = serde_json::from_str(&data)?;
+    /*
+    Read an atlas index file. Both binary index files and the older JSON
+    index files are read. Returns CrsMismatch if the maps of the file have
+    different CRSs.
+     */
+    pub fn read_atlas(file: &str) -> Result<Self> {
+        let data = fs::read(file)?;
+
+        let s: Self = if indexfile::is_index_file(&data) {
+            let maps = indexfile::read_index(file, &data)?;
+            let mut s = Self::new_empty(None);
+            s.maps = RwLock::new(MapIndex::new(
+                maps.into_iter().map(Arc::new).collect()));
+            s
+        }
+        else {
+            serde_json::from_slice(&data)?
+        };
+
+        s.crs()?;
+
 	Ok(s)
     }
 
+    /*
+    Common CRS of maps, given as file name and EPSG code. Maps with an unknown
+    CRS are taken to match any CRS. Returns CrsMismatch if the maps have
+    different CRSs, since maps are not reprojected.
+     */
+    fn common_crs<'a, I>(maps: I) -> Result<Option<u32>>
+    where I: IntoIterator<Item = (&'a str, Option<u32>)>
+    {
+        let mut crs = None;
+
+        for (fname, c) in maps {
+            match (crs, c) {
+                (Some(a), Some(b)) if a != b =>
+                    return Err(Error::CrsMismatch(fname.to_string(), b, a)
+                               .into()),
+                (None, Some(_)) => crs = c,
+                _ => {},
+            }
+        }
+
+        Ok(crs)
+    }
+
+    /*
+    EPSG code of the CRS of the maps in the atlas, including index files not
+    read yet, or None if unknown.
+     */
+    pub fn crs(&self) -> Result<Option<u32>> {
+        let maps = self.distinct_maps();
+        let pending = self.pending.read().unwrap();
+
+        Self::common_crs(
+            maps.iter().map(|m| (m.fname.as_str(), m.crs))
+                .chain(pending.iter().map(|e| (e.file.as_str(), e.crs))))
+    }
+
+    // Write the maps of the atlas to a binary index file
     pub fn write_atlas(&self, file: &str) -> Result<()> {
-        let data = serde_json::to_string(&self)?;
-        fs::write(&file, data).expect("Unable to write file");
-	Ok(())
+        let maps = self.distinct_maps();
+        indexfile::write_index(file, maps.iter().map(|m| m.as_ref()))
     }
 
-    fn append(&mut self, other: &Atlas) {
-	for (h, a) in other.maps.iter() {
-	    if !self.maps.contains_key(&h) {
-		self.maps.insert(*h, Vec::new());
-	    }
-	    for m in a.iter() {
-		self.maps.get_mut(&h).unwrap().push(Rc::clone(&m));
-	    }
-	}
+    /*
+    Read the index file at path. A JSON index file is migrated to a binary
+    index file next to it, which is read instead from then on. Failing to
+    write the binary index file (e.g. in a read-only map directory) is
+    reported, but does not fail the read.
+     */
+    fn read_or_migrate(path: &str, tx: Option<&MsgSender>) -> Result<Self> {
+        let Some(stem) = path.strip_suffix(".json") else {
+            return Self::read_atlas(path);
+        };
+
+        let a = Self::read_atlas(path)?;
+
+        if let Err(e) = a.write_atlas(&format!("{}.bin", stem)) &&
+            let Some(some_tx) = tx {
+            some_tx.send(format!("Failed to migrate index {}: {}",
+                                 path, e)).unwrap();
+        }
+
+        Ok(a)
+    }
+
+    // Resolution of some map in the atlas, or None if it has no maps
+    fn resolution(&self) -> Option<f32> {
+	self.distinct_maps().first().map(|m| m.resolution())
     }
 
-    fn resolution(&self) -> f32 {
-	self.maps.values().next().unwrap().into_iter().next().
-	    unwrap().resolution()
+    // Bounding box of the maps in the atlas
+    fn extent(&self) -> Option<(Coord, Coord)> {
+	let maps = self.distinct_maps();
+	let first = maps.first()?;
+	let (mut nw, mut se) = (first.nw, first.se);
+
+	for m in maps.iter() {
+	    nw = Coord::new(nw.e.min(m.nw.e), nw.n.max(m.nw.n));
+	    se = Coord::new(se.e.max(m.se.e), se.n.min(m.se.n));
+	}
+
+	Some((nw, se))
     }
     
     fn new_empty(tx: Option<MsgSender>) -> Self {
 	Self {
-	    maps: HashMap::new(),
+	    folder: Arc::clone(&MAPFOLDER),
+	    maps: Default::default(),
+            pending: Default::default(),
             mockup: false,
             tx: tx,
+            interpolation: Interpolation::Nearest,
+            overlap_policy: OverlapPolicy::FinestResolution,
+            block_size: BlockSize::Whole,
+            storage: SampleStorage::Float,
+            cache: None,
+            memory_budget: None,
+            resident: Mutex::new(HashMap::new()),
+            clock: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
 	}
     }
     
+    // Create an atlas of the given resolution in the global map folder
     pub fn new(resolution: f32, tx: Option<MsgSender>) -> Result<Self> {
-	let mut s = Self::new_empty(tx.clone());
-	let mut i = 0;
+	Self::new_layered(&[resolution], tx)
+    }
+
+    // Create a layered atlas in the global map folder, set by set_map_dir
+    pub fn new_layered(resolutions: &[f32], tx: Option<MsgSender>)
+                       -> Result<Self> {
+	Self::new_in_folder(Arc::clone(&MAPFOLDER), resolutions, tx)
+    }
 
-	// Read directory, for each atlas file, append it to our atlas
-	for fentry in fs::read_dir(map_dir())? {
+    /*
+    Create an atlas from the atlas files of all the given resolutions.
+    Lookups use the finest resolution covering the coordinate, and fall back
+    to coarser resolutions.
+
+    The index files are found through the catalogue of the map folder, and
+    are only read when a query touches their extent. Index files missing
+    from the catalogue, or changed since it was written, are read and added
+    to the catalogue. Maps are loaded from the same folder.
+     */
+    pub fn new_in_folder(folder: Arc<MapFolder>, resolutions: &[f32],
+                         tx: Option<MsgSender>) -> Result<Self> {
+	let dir = folder.dir()?;
+	let catalogue_file = format!("{}{}", dir, CATALOGUE_FILE);
+	let old = Catalogue::read(&catalogue_file).unwrap_or_default();
+	let mut catalogue = Catalogue::default();
+
+	let mut files = Vec::new();
+	for fentry in fs::read_dir(&dir)? {
             let path = fentry?.path();
-            if path.is_dir() {
-		continue;
+            if !path.is_dir() {
+		files.push(path.file_name().unwrap().to_str().unwrap()
+			   .to_string());
             }
+	}
 
+	// Find each atlas file in the directory. JSON index files already
+	// migrated to binary index files are skipped.
+	for file in files.iter() {
 	    // For some reason, !path.ends_with("atlas.json") does not work!
-	    if !path.to_str().unwrap().ends_with("atlas.json") {
+	    let migrated = file.strip_suffix(".json")
+		.is_some_and(|stem| files.contains(&format!("{}.bin", stem)));
+	    if !(file.ends_with("atlas.bin") || file.ends_with("atlas.json")) ||
+		migrated {
 		continue;
 	    }
 
-	    let a = Self::read_atlas(path.to_str().unwrap())?;
-
-	    // Check some map in atlas to determine if it has the right
-	    // resolution.
-	    if a.resolution() != resolution {
+	    let path = format!("{}{}", dir, file);
+	    let stamp = file_stamp(Path::new(&path))?;
+	    if let Some(e) = old.get(file, stamp) {
+		catalogue.entries.push(e.clone());
 		continue;
 	    }
 
-	    s.append(&a);
+	    let a = Self::read_or_migrate(&path, tx.as_ref())?;
+	    let origin = Coord::new(0.0, 0.0);
+	    let (nw, se) = a.extent().unwrap_or((origin, origin));
+	    catalogue.entries.push(CatalogueEntry {
+		file: file.clone(),
+		stamp,
+		resolution: a.resolution().unwrap_or(0.0),
+		nw,
+		se,
+		maps: a.distinct_maps().len(),
+		crs: a.crs()?,
+	    });
+	}
 
-	    i += 1;
+	if catalogue != old &&
+	    let Err(e) = catalogue.write(&catalogue_file) &&
+	    let Some(some_tx) = tx.as_ref() {
+	    some_tx.send(format!("Failed to write catalogue {}: {}",
+				 catalogue_file, e)).unwrap();
 	}
 
+	// Check the resolution of each atlas file against the requested
+	// resolutions.
+	let pending: Vec<_> = catalogue.entries.into_iter()
+	    .filter(|e| e.maps > 0 && resolutions.contains(&e.resolution))
+	    .collect();
+	let i = pending.len();
+
+	// Refuse to mix maps with different CRSs
+	Self::common_crs(pending.iter().map(|e| (e.file.as_str(), e.crs)))?;
+
+	let mut s = Self::new_empty(tx.clone());
+	s.folder = folder;
+	s.pending = RwLock::new(pending);
+
         if let Some(some_tx) = tx {
             some_tx.send(format!(
-	        "Read metadata for {} atlases with resolution {}.",
-                i, resolution)).unwrap();
+	        "Found {} atlases with resolution {:?}.",
+                i, resolutions)).unwrap();
         }
 
 	Ok(s)
     }
 
-    pub fn is_empty(&self) -> bool {
-	self.maps.is_empty()
+    /*
+    Spatial index of the maps, after reading the pending index files
+    overlapping the area between the corners nw and se. Index files that fail
+    to read are reported on the message channel, and not tried again.
+     */
+    fn index(&self, nw: &Coord, se: &Coord) -> RwLockReadGuard<'_, MapIndex> {
+        let touched = |e: &CatalogueEntry| e.intersects(nw, se);
+
+        if self.pending.read().unwrap().iter().any(touched) {
+            // Read the index files while holding the lock, so that other
+            // threads touching them wait for them instead of reading them
+            // again.
+            let mut pending = self.pending.write().unwrap();
+            let (read, keep): (Vec<_>, Vec<_>) =
+                pending.drain(..).partition(touched);
+            *pending = keep;
+
+            for e in read {
+                // The CRS of the index file was checked against the others
+                // when the atlas was created, but the file may have changed
+                // since.
+                let a = self.folder.path(&e.file)
+                    .and_then(|path| Self::read_atlas(&path))
+                    .and_then(|a| {
+                        let maps = a.distinct_maps();
+                        Self::common_crs(
+                            [(e.file.as_str(), e.crs)].into_iter().chain(
+                                maps.iter().map(|m| (m.fname.as_str(), m.crs))))?;
+                        Ok(a)
+                    });
+
+                match a {
+                    Ok(a) => {
+                        let mut maps = self.maps.write().unwrap();
+                        for m in a.distinct_maps() {
+                            maps.insert(m);
+                        }
+                    },
+                    Err(err) => if let Some(some_tx) = &self.tx {
+                        some_tx.send(format!("Failed to read index {}: {}",
+                                             e.file, err)).unwrap();
+                    },
+                }
+            }
+        }
+
+        self.maps.read().unwrap()
     }
 
-    pub fn load_images(&self, coord: &Coord)
-                       -> Result<()> {
-	let h = Map::coord_to_hash(coord);
+    // Like MapIndex::at, reading the index files around coord first
+    fn maps_at(&self, coord: &Coord) -> Vec<Arc<Map>> {
+        self.index(coord, coord).at(coord)
+    }
+
+    // Maps containing coord, in the order of the overlap policy
+    fn candidates(&self, coord: &Coord) -> Vec<Arc<Map>> {
+        let mut maps = self.maps_at(coord);
+        self.overlap_policy.order(&mut maps);
+        maps
+    }
 
-        if !self.maps.contains_key(&h) {
-	    // No maps for coord. Do nothing.
-            return Ok(());
+    // Return the resolutions of the maps in the atlas, finest first
+    pub fn resolutions(&self) -> Vec<f32> {
+	let mut v: Vec<f32> = Vec::new();
+
+	let pending = self.pending.read().unwrap();
+	let resolutions = self.distinct_maps().iter().map(|m| m.resolution())
+	    .chain(pending.iter().map(|e| e.resolution)).collect::<Vec<_>>();
+
+	for r in resolutions {
+	    if !v.contains(&r) {
+		v.push(r);
+	    }
 	}
 
-	for m in self.maps.get(&h).unwrap().iter() {
+	v.sort_by(|a, b| a.total_cmp(b));
+	v
+    }
+
+    pub fn is_empty(&self) -> bool {
+	self.maps.read().unwrap().is_empty() &&
+	    self.pending.read().unwrap().is_empty()
+    }
+
+    pub fn load_images(&self, coord: &Coord)
+                       -> Result<()> {
+	for m in self.maps_at(coord).iter() {
 	    if !m.is_loaded() {
-		m.load_image(self.tx.as_ref())?;
+		self.load_map(m)?;
 	    }
 	}
 
 	Ok(())
     }
 
-    pub fn has_maps(&self, coord: &Coord) -> bool {
-	let h = Map::coord_to_hash(coord);
+    // Set the interpolation used by lookup and lookup_with_gradient
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.interpolation = interpolation;
+    }
+
+    pub fn interpolation(&self) -> Interpolation {
+        self.interpolation
+    }
+
+    // Set the policy for choosing between maps overlapping at a coordinate
+    pub fn set_overlap_policy(&mut self, policy: OverlapPolicy) {
+        self.overlap_policy = policy;
+    }
+
+    pub fn overlap_policy(&self) -> &OverlapPolicy {
+        &self.overlap_policy
+    }
+
+    /*
+    Set the size of the blocks map images are loaded in. With a block size
+    other than BlockSize::Whole, lookups only read the blocks they need.
+    Images already partly loaded keep their block size until unloaded.
+     */
+    pub fn set_block_size(&mut self, block_size: BlockSize) {
+        self.block_size = block_size;
+    }
 
-        self.maps.contains_key(&h)
+    pub fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+
+    /*
+    Set how the samples of map images are stored in memory. Quantized images
+    take half the memory of float images, at the cost of a small error,
+    reported by memory_usage. Images already loaded keep their storage until
+    unloaded.
+     */
+    pub fn set_sample_storage(&mut self, storage: SampleStorage) {
+        self.storage = storage;
+    }
+
+    pub fn sample_storage(&self) -> SampleStorage {
+        self.storage
+    }
+
+    // Set the map folder maps are loaded from. Atlases read with read_atlas
+    // use the global map folder until set.
+    pub fn set_map_folder(&mut self, folder: Arc<MapFolder>) {
+        self.folder = folder;
+    }
+
+    pub fn map_folder(&self) -> &Arc<MapFolder> {
+        &self.folder
+    }
+
+    /*
+    Set a directory for caching decoded map images. Cached images are memory
+    mapped instead of being read from the GeoTIFF files, and are refreshed
+    when the source file changes. Maps are always loaded as a whole when
+    cached. None disables the cache.
+     */
+    pub fn set_cache_dir(&mut self, dir: Option<&str>) {
+        self.cache = dir.map(RasterCache::new);
+    }
+
+    /*
+    Set the maximum number of bytes of map images to keep in memory. When
+    exceeded, the least recently used images are unloaded. None means no
+    limit.
+     */
+    pub fn set_memory_budget(&mut self, budget: Option<usize>) {
+        self.memory_budget = budget;
+    }
+
+    pub fn memory_budget(&self) -> Option<usize> {
+        self.memory_budget
+    }
+
+    // Number of map images unloaded to stay within the memory budget
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    // Return each map of the atlas once. Maps of index files not read yet
+    // are not included.
+    fn distinct_maps(&self) -> Vec<Arc<Map>> {
+        self.maps.read().unwrap().maps()
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage::default();
+
+        for m in self.distinct_maps() {
+            if m.is_resident() {
+                usage.maps += 1;
+                usage.bytes += m.image_bytes();
+                usage.max_error = usage.max_error.max(m.max_error());
+            }
+        }
+
+        usage
+    }
+
+    // Unload the image of a map given by its file name. Returns true if the
+    // map was loaded.
+    pub fn unload_map(&self, fname: &str) -> bool {
+        let maps: Vec<_> = self.distinct_maps().into_iter()
+            .filter(|m| m.fname == fname)
+            .collect();
+
+        self.unload(&maps) > 0
+    }
+
+    // Unload the images of all maps overlapping the area between the corners
+    // nw and se. Returns the number of images unloaded.
+    pub fn unload_area(&self, nw: &Coord, se: &Coord) -> usize {
+        let maps: Vec<_> = self.distinct_maps().into_iter()
+            .filter(|m| m.intersects(nw, se))
+            .collect();
+
+        self.unload(&maps)
+    }
+
+    // Unload all map images of the atlas. Returns the number of images
+    // unloaded.
+    pub fn flush_maps(&self) -> usize {
+        self.unload(&self.distinct_maps())
+    }
+
+    // Unload the given maps
+    fn unload(&self, maps: &[Arc<Map>]) -> usize {
+        let mut n = 0;
+        let mut resident = self.resident.lock().unwrap();
+
+        for m in maps.iter().filter(|m| m.is_resident()) {
+            m.unload_image();
+            resident.remove(&m.fname);
+            n += 1;
+        }
+
+        if let Some(some_tx) = &self.tx {
+            some_tx.send(format!("Unloaded {} maps", n)).unwrap();
+        }
+
+        n
+    }
+
+    // Load the whole image of a map
+    fn load_map(&self, m: &Arc<Map>) -> Result<()> {
+        let (w, h) = m.size();
+        self.load_map_windows(m, &[(0, 0, w as isize - 1, h as isize - 1)])
+    }
+
+    // Load the blocks of a map image overlapping the given pixel windows
+    fn load_map_windows(&self, m: &Arc<Map>,
+                        windows: &[(isize, isize, isize, isize)])
+                        -> Result<()> {
+        m.touch(self.clock.fetch_add(1, Ordering::Relaxed));
+        for w in windows {
+            m.load_window(*w, self.block_size, self.storage, &self.folder,
+                          self.cache.as_ref(), self.tx.as_ref())?;
+        }
+
+        self.resident.lock().unwrap()
+            .insert(m.fname.clone(), Arc::clone(m));
+        self.enforce_budget(m);
+
+        Ok(())
+    }
+
+    // Unload least recently used images until we are within budget. The
+    // map given by keep was just loaded, and is never evicted.
+    fn enforce_budget(&self, keep: &Arc<Map>) {
+        let budget = match self.memory_budget {
+            Some(b) => b,
+            None => return,
+        };
+
+        let mut resident = self.resident.lock().unwrap();
+        resident.retain(|_, m| m.is_resident());
+        let mut used: usize = resident.values().map(|m| m.image_bytes()).sum();
+
+        while used > budget {
+            let lru = resident.values()
+                .filter(|m| !Arc::ptr_eq(m, keep))
+                .min_by_key(|m| m.last_used())
+                .map(|m| m.fname.clone());
+
+            let Some(fname) = lru else {
+                break;
+            };
+
+            let m = resident.remove(&fname).unwrap();
+            used -= m.image_bytes();
+            m.unload_image();
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+
+            if let Some(some_tx) = &self.tx {
+                let _ = some_tx.send(format!("Unloaded file {}",
+                                             m.fname));
+            }
+        }
+    }
+
+    /*
+    Run a lookup function for the given coordinates on a map, loading the
+    needed parts of the map image first if needed. If the image is evicted by
+    another thread before the lookup gets to it, it is loaded again, up to
+    MAX_RELOADS times. MapNotLoaded is returned if it is still evicted.
+     */
+    fn lookup_map<T, F>(&self, m: &Arc<Map>, coords: &[Coord], f: F)
+                        -> Result<T>
+    where F: Fn(&Map) -> Result<T>
+    {
+        m.touch(self.clock.fetch_add(1, Ordering::Relaxed));
+
+        // The first load is not a reload
+        let mut loads = 0;
+
+        loop {
+            match f(m) {
+                Err(e) if loads <= MAX_RELOADS &&
+                    matches!(e.downcast_ref::<Error>(),
+                             Some(Error::MapNotLoaded(_))) => {
+                    loads += 1;
+                    let windows: Vec<_> = coords.iter()
+                        .map(|c| m.window(c, self.interpolation))
+                        .collect();
+                    self.load_map_windows(m, &windows)?;
+                },
+                r => return r,
+            }
+        }
+    }
+
+    // Like MapIndex::in_area, reading the index files within the area first
+    pub(crate) fn maps_in_area(&self, nw: &Coord, se: &Coord) -> Vec<Arc<Map>> {
+        self.index(nw, se).in_area(nw, se)
+    }
+
+    // Maps along a path, in the order the path visits them
+    pub(crate) fn maps_along(&self, path: &[Coord]) -> Vec<Arc<Map>> {
+        let Some((nw, se)) = Self::bounds(path, 0.0) else {
+            return Vec::new();
+        };
+
+        self.index(&nw, &se).along(path)
+    }
+
+    /*
+    Load the images of the given maps, within the memory budget. Failures are
+    reported on the message channel, and do not stop the loading of the
+    remaining maps.
+     */
+    pub(crate) fn prefetch_maps(&self, maps: &[Arc<Map>]) {
+        for m in maps {
+            if m.is_loaded() {
+                continue;
+            }
+            if let Err(e) = self.load_map(m) &&
+                let Some(some_tx) = &self.tx {
+                // Nobody may be listening to a background prefetch
+                let _ = some_tx.send(format!("Prefetch of {} failed: {}",
+                                             m.fname, e));
+            }
+        }
+    }
+
+    // Load all maps overlapping the area between the corners nw and se
+    pub fn preload_area(&self, nw: &Coord, se: &Coord)
+                        -> Result<PreloadSummary> {
+        self.preload(&self.maps_in_area(nw, se))
+    }
+
+    // Load all maps overlapping the polygon
+    pub fn preload_polygon(&self, polygon: &[Coord]) -> Result<PreloadSummary> {
+        let Some((nw, se)) = Self::bounds(polygon, 0.0) else {
+            return Ok(PreloadSummary::default());
+        };
+
+        let maps: Vec<_> = self.maps_in_area(&nw, &se).into_iter()
+            .filter(|m| geometry::rect_intersects_polygon(
+                &m.nw, &m.se, polygon))
+            .collect();
+
+        self.preload(&maps)
+    }
+
+    // Load all maps within distance buffer of the polyline
+    pub fn preload_polyline(&self, line: &[Coord], buffer: f64)
+                            -> Result<PreloadSummary> {
+        let Some((nw, se)) = Self::bounds(line, buffer) else {
+            return Ok(PreloadSummary::default());
+        };
+
+        let maps: Vec<_> = self.maps_in_area(&nw, &se).into_iter()
+            .filter(|m| {
+                if line.len() == 1 {
+                    return geometry::segment_rect_distance(
+                        &line[0], &line[0], &m.nw, &m.se) <= buffer;
+                }
+                line.windows(2).any(|w| geometry::segment_rect_distance(
+                    &w[0], &w[1], &m.nw, &m.se) <= buffer)
+            })
+            .collect();
+
+        self.preload(&maps)
+    }
+
+    // Bounding box of the points, grown by margin
+    fn bounds(points: &[Coord], margin: f64) -> Option<(Coord, Coord)> {
+        let first = points.first()?;
+        let (mut nw, mut se) = (*first, *first);
+
+        for p in points {
+            nw = Coord::new(nw.e.min(p.e), nw.n.max(p.n));
+            se = Coord::new(se.e.max(p.e), se.n.min(p.n));
+        }
+
+        Some((nw + Coord::new(-margin, margin), se + Coord::new(margin, -margin)))
+    }
+
+    // Load the given maps, reporting progress on the message channel
+    fn preload(&self, maps: &[Arc<Map>]) -> Result<PreloadSummary> {
+        let evictions = self.evictions();
+        let mut summary = PreloadSummary {
+            maps: maps.len(),
+            ..Default::default()
+        };
+
+        for (i, m) in maps.iter().enumerate() {
+            if let Some(some_tx) = &self.tx {
+                some_tx.send(format!("Preloading map {}/{}: {}",
+                                     i + 1, maps.len(), m.fname)).unwrap();
+            }
+
+            if !m.is_loaded() {
+                self.load_map(m)?;
+                summary.loaded += 1;
+            }
+        }
+
+        summary.evicted = self.evictions() - evictions;
+        summary.bytes = maps.iter().map(|m| m.image_bytes()).sum();
+
+        Ok(summary)
+    }
+
+    pub fn has_maps(&self, coord: &Coord) -> bool {
+        !self.maps_at(coord).is_empty()
     }
     
     pub fn has_images(&self, coord: &Coord) -> bool {
-	let h = Map::coord_to_hash(coord);
+	let maps = self.maps_at(coord);
 
-        if !self.maps.contains_key(&h) {
+        if maps.is_empty() {
 	    // No maps available for coord
             return false;
 	}
 
-	for m in self.maps.get(&h).unwrap().iter() {
+	for m in maps.iter() {
 	    if !m.is_loaded() {
 		return false;
 	    }
@@ -197,21 +867,35 @@ impl Atlas {
 	true
     }
     
-    pub fn lookup_maps(&self, coord: &Coord) -> Result<&Vec::<Rc<Map>>> {
-	let h = Map::coord_to_hash(coord);
-        if let Some(m) = self.maps.get(&h) {
-            return Ok(m);
+    // Maps a lookup of coord may use. Fails if there are none.
+    pub fn lookup_maps(&self, coord: &Coord) -> Result<Vec::<Arc<Map>>> {
+	let maps = self.maps_at(coord);
+        if maps.is_empty() {
+	    return Err(Error::MapNotFound(*coord).into());
         }
-        else {
-	    return Err(Error::MapNotFound(coord.clone()).into());
+
+        Ok(maps)
+    }
+
+    /*
+    Surveys of the maps containing coord, in the order of the overlap policy.
+    The first survey is the one lookups use, unless the values of the maps
+    are averaged.
+     */
+    pub fn surveys(&self, coord: &Coord) -> Result<Vec<SurveyInfo>> {
+        let maps = self.candidates(coord);
+        if maps.is_empty() {
+	    return Err(Error::MapNotFound(*coord).into());
         }
+
+        Ok(maps.iter().map(|m| m.survey.clone()).collect())
     }
 
     pub fn lookup_mockup(&self, coord: &Coord) -> Result<f32> {
         let h = ((coord.n*PI/10000.0).sin() +
                  (coord.e*PI/20000.0).sin())*500.0 + 1000.0;
 
-        Ok(h)
+        Ok(h as f32)
     }
 
     pub fn lookup_with_gradient_mockup(&self, coord: &Coord)
@@ -233,81 +917,347 @@ impl Atlas {
              (coord.e*PI/20000.0).sin())*500.0
         )*0.5;
 
-        Ok((h, dx, dy))
+        Ok((h as f32, dx as f32, dy as f32))
     }
 
     pub fn lookup(&self, coord: &Coord) -> Result<f32> {
+        Ok(self.lookup_with_resolution(coord)?.0)
+    }
+
+    pub fn lookup_with_gradient(&self, coord: &Coord)
+                                -> Result<(f32, f32, f32)> {
+        Ok(self.lookup_with_gradient_and_resolution(coord)?.0)
+    }
+
+    /*
+    Lookup height of coordinate, and the resolution of the map which answered
+    the lookup. The mockup atlas reports a resolution of 1 m.
+     */
+    pub fn lookup_with_resolution(&self, coord: &Coord)
+                                  -> Result<(f32, f32)> {
         if self.mockup {
-            return self.lookup_mockup(coord);
+            return Ok((self.lookup_mockup(coord)?, 1.0));
         }
 
-	/*
-        Lookup function for coordinates. Find map which covers coordinates,
-        lookup height.
-	 */
-        let h = Map::coord_to_hash(coord);
+        let ((h, _, _), m, _) = self.lookup_candidates(coord)?;
+        Ok((h, m.resolution()))
+    }
 
-        if !self.maps.contains_key(&h) {
-	    // No maps available for coord
-            return Err(Error::MapNotFound(coord.clone()).into());
-	}
+    pub fn lookup_with_gradient_and_resolution(&self, coord: &Coord)
+                                               -> Result<((f32, f32, f32), f32)> {
+        if self.mockup {
+            return Ok((self.lookup_with_gradient_mockup(coord)?, 1.0));
+        }
 
-	for m in self.maps.get(&h).unwrap().iter() {
-            match m.lookup(coord) {
-                Ok(r) => return Ok(r),
-                Err(e) => {
-                    if let Some(err) = e.downcast_ref::<Error>() {
-                        if let Error::MapNotLoaded(_) = err {
-                            // Load map and try again
-                            m.load_image(self.tx.as_ref())?;
-                            if let Ok(r) = m.lookup(coord) {
-                                return Ok(r)
-                            }
-                        }
-                    }
+        let (r, m, _) = self.lookup_candidates(coord)?;
+        Ok((r, m.resolution()))
+    }
+
+    /*
+    Lookup height and gradient of coordinate, together with the map, pixel
+    and interpolation which produced them. This is slower than lookup, and
+    meant for inspecting values rather than for bulk lookups. The mockup
+    atlas reports pixel (0, 0) of a map named "mockup", with a resolution of
+    1 m.
+     */
+    pub fn lookup_with_provenance(&self, coord: &Coord) -> Result<LookupInfo> {
+        if self.mockup {
+            let (height, dx, dy) = self.lookup_with_gradient_mockup(coord)?;
+            return Ok(LookupInfo {
+                height,
+                gradient: (dx, dy),
+                fname: String::from("mockup"),
+                zipfile: String::new(),
+                pixel: (0, 0),
+                centre: *coord,
+                resolution: 1.0,
+                interpolation: self.interpolation,
+                maps: 1,
+            });
+        }
+
+        let ((height, dx, dy), m, maps) = self.lookup_candidates(coord)?;
+        let (px, py) = m.pixel(coord);
+        let (x, y) = (px.floor() as usize, py.floor() as usize);
+
+        Ok(LookupInfo {
+            height,
+            gradient: (dx, dy),
+            fname: m.fname.clone(),
+            zipfile: m.zipfile.clone(),
+            pixel: (x, y),
+            centre: m.pixel_centre(x as isize, y as isize),
+            resolution: m.resolution(),
+            interpolation: self.interpolation,
+            maps,
+        })
+    }
+
+    /*
+    Lookup heights of a batch of coordinates. Points are grouped on the map
+    covering them, so that each map image is loaded and read once for the
+    batch. Returns a result for each point. Misses are given as MapNotFound.
+    Failures, e.g. of maps that can not be loaded, are given for the points
+    they affect, and do not stop the lookup of the other points.
+     */
+    pub fn lookup_batch<'a, I>(&self, coords: I)
+                               -> Result<Vec<std::result::Result<f32, Error>>>
+    where I: IntoIterator<Item = &'a Coord>
+    {
+        Ok(self.lookup_batch_with_gradient(coords)?.into_iter()
+           .map(|r| r.map(|(h, _, _)| h))
+           .collect())
+    }
+
+    /*
+    Lookup heights of a batch of coordinates into the buffer out, which must
+    have the same length as coords. Misses are set to NaN. Returns the number
+    of misses.
+     */
+    pub fn lookup_batch_into(&self, coords: &[Coord], out: &mut [f32])
+                             -> Result<usize> {
+        if coords.len() != out.len() {
+            return Err(Error::Generic(format!(
+                "Output buffer has length {}, expected {}",
+                out.len(), coords.len())).into());
+        }
+
+        let mut misses = 0;
+
+        for (o, r) in out.iter_mut().zip(self.lookup_batch(coords)?) {
+            match r {
+                Ok(h) => *o = h,
+                Err(_) => {
+                    *o = f32::NAN;
+                    misses += 1;
                 },
             }
-	}
+        }
 
-        Err(Error::MapNotFound(coord.clone()).into())
+        Ok(misses)
     }
 
-    pub fn lookup_with_gradient(&self, coord: &Coord)
-                                -> Result<(f32, f32, f32)> {
+    pub fn lookup_batch_with_gradient<'a, I>(&self, coords: I)
+        -> Result<Vec<std::result::Result<HeightGradient, Error>>>
+    where I: IntoIterator<Item = &'a Coord>
+    {
+        let coords: Vec<Coord> = coords.into_iter().copied().collect();
+
         if self.mockup {
-            return self.lookup_with_gradient_mockup(coord);
+            return Ok(coords.iter().map(|c| {
+                Ok(self.lookup_with_gradient_mockup(c).unwrap())
+            }).collect());
         }
 
-	/*
-        Lookup function for coordinates. Find map which covers coordinates,
-        lookup height.
-	 */
-        let h = Map::coord_to_hash(coord);
+        let mut ret: Vec<std::result::Result<HeightGradient, Error>> =
+            coords.iter().map(|c| Err(Error::MapNotFound(*c))).collect();
+
+        // Group points on the first candidate map containing them, keyed on
+        // file name. The candidates are ordered by the overlap policy.
+        let mut groups: HashMap<String, (Arc<Map>, Vec<usize>)> =
+            HashMap::new();
+        let average = self.overlap_policy == OverlapPolicy::Average;
+
+        for (i, c) in coords.iter().enumerate() {
+            let candidates = self.candidates(c);
+            let Some(m) = candidates.iter().find(|m| m.contains(c)) else {
+                continue;
+            };
+
+            if !average && m.covers(c, self.interpolation) {
+                groups.entry(m.fname.clone())
+                    .or_insert_with(|| (Arc::clone(m), Vec::new()))
+                    .1.push(i);
+            }
+            else {
+                // Point on a map edge, stitch neighbouring maps. Points are
+                // also looked up one by one when averaging maps.
+                ret[i] = self.lookup_candidates(c).map(|(r, _, _)| r)
+                    .map_err(|e| point_error(e.as_ref()));
+            }
+        }
 
-        if !self.maps.contains_key(&h) {
-	    // No maps available for coord
-            return Err(Error::MapNotFound(coord.clone()).into());
-	}
+        for (m, indices) in groups.into_values() {
+            let group: Vec<Coord> = indices.iter().map(|i| coords[*i]).collect();
+            let results = self.lookup_map(
+                &m, &group, |m| m.lookup_many(&group, self.interpolation));
 
-	for m in self.maps.get(&h).unwrap().iter() {
-            match m.lookup_with_gradient(coord) {
-                Ok(r) => return Ok(r),
+            match results {
+                Ok(results) => {
+                    for (i, r) in indices.into_iter().zip(results) {
+                        ret[i] = r;
+                    }
+                },
                 Err(e) => {
-                    if let Some(err) = e.downcast_ref::<Error>() {
-                        if let Error::MapNotLoaded(_) = err {
-                            // Load map and try again
-                            m.load_image(self.tx.as_ref())?;
-                            if let Ok(r) = m.lookup_with_gradient(coord) {
-                                return Ok(r)
-                            }
-                        }
+                    let e = point_error(e.as_ref());
+                    for i in indices {
+                        ret[i] = Err(e.clone());
                     }
                 },
             }
+        }
+
+        // Try the other maps for points with NoData on the first map
+        for (i, c) in coords.iter().enumerate() {
+            if let Err(Error::NoData(..)) = ret[i] {
+                ret[i] = self.lookup_candidates(c).map(|(r, _, _)| r)
+                    .map_err(|e| point_error(e.as_ref()));
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /*
+    Lookup function for coordinates. Try the candidate maps for the
+    coordinate, in the order of the overlap policy, until one of them covers
+    it. With the Average policy, the values of all covering maps are
+    averaged. Points near the edge of a map are looked up by stitching the
+    map together with its neighbours. Maps with NoData at the coordinate are
+    skipped, and NoData is returned if no other map covers it. Return the
+    height and gradient together with the map which answered the lookup (the
+    finest map, when averaging) and the number of maps the values are
+    averaged over.
+     */
+    fn lookup_candidates(&self, coord: &Coord)
+                         -> Result<(HeightGradient, Arc<Map>, usize)> {
+        let average = self.overlap_policy == OverlapPolicy::Average;
+        let mut found: Vec<(HeightGradient, &Arc<Map>)> = Vec::new();
+        let mut nodata = None;
+        let candidates = self.candidates(coord);
+
+	for m in candidates.iter() {
+            let r = if m.covers(coord, self.interpolation) {
+                self.lookup_map(
+                    m, &[*coord],
+                    |m| m.lookup_with_gradient_interpolated(
+                        coord, self.interpolation)).map(Some)
+            }
+            else if m.contains(coord) {
+                self.lookup_stitched(m, coord)
+            }
+            else {
+                Ok(None)
+            };
+
+            let r = match r {
+                Err(e) if is_nodata(e.as_ref()) => {
+                    nodata = Some(e);
+                    continue;
+                },
+                r => r?,
+            };
+
+            if let Some(r) = r {
+                if !average {
+                    return Ok((r, Arc::clone(m), 1));
+                }
+                found.push((r, m));
+            }
 	}
 
-        Err(Error::MapNotFound(coord.clone()).into())
+        let Some(&(_, first)) = found.first() else {
+            return Err(nodata.unwrap_or(Error::MapNotFound(*coord).into()));
+        };
+
+        let n = found.len() as f32;
+        let (h, dx, dy) = found.iter().fold(
+            (0.0, 0.0, 0.0),
+            |a, ((h, dx, dy), _)| (a.0 + h, a.1 + dx, a.2 + dy));
+
+        Ok(((h/n, dx/n, dy/n), Arc::clone(first), found.len()))
     }
+
+    /*
+    Lookup height and gradient of a coordinate within the primary map, taking
+    the samples outside of the primary map from neighbouring maps with the
+    same resolution. Returns None if some of the samples are not covered by
+    any map, and NoData if some of the samples the height depends on are
+    NoData. As in lookup_map, maps evicted by other threads are loaded again
+    up to MAX_RELOADS times before giving up with MapNotLoaded.
+     */
+    fn lookup_stitched(&self, primary: &Arc<Map>, coord: &Coord)
+                       -> Result<Option<(f32, f32, f32)>> {
+        let (px, py) = primary.pixel(coord);
+        let (x0, y0, x1, y1) = self.interpolation.window(px, py);
+
+        // Resolve each pixel of the window to a map and a pixel of that map
+        let mut pixels = HashMap::new();
+
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let c = primary.pixel_centre(x, y);
+                let Some(m) = self.neighbour(primary, &c) else {
+                    return Ok(None);
+                };
+                let (mx, my) = m.pixel(&c);
+                pixels.insert((x, y), (m, mx as usize, my as usize));
+            }
+        }
+
+        // Pixels to load, for each map
+        let mut windows: HashMap<&str, (&Arc<Map>, Vec<_>)> = HashMap::new();
+        for (m, mx, my) in pixels.values() {
+            let (x, y) = (*mx as isize, *my as isize);
+            windows.entry(&m.fname).or_insert_with(|| (m, Vec::new()))
+                .1.push((x, y, x, y));
+        }
+
+        for _ in 0..=MAX_RELOADS {
+            for (m, w) in windows.values() {
+                self.load_map_windows(m, w)?;
+            }
+
+            let nodata = Cell::new(None);
+            let r = interpolate(self.interpolation, px, py, |x, y| {
+                let (m, mx, my) = pixels.get(&(x, y))?;
+                let v = m.pixel_value(*mx, *my)?;
+                if m.is_nodata(v) {
+                    nodata.set(Some(m));
+                    return Some(f32::NAN);
+                }
+                Some(v)
+            });
+
+            if let Some((h, _, _)) = r && h.is_nan() {
+                let m = nodata.get().unwrap_or(primary);
+                return Err(Error::NoData(*coord, m.fname.clone()).into());
+            }
+
+            if let Some((h, dx, dy)) = r {
+                let (de, dn) = primary.model_gradient(dx, dy);
+                return Ok(Some((h, de, dn)));
+            }
+
+            // Some map was unloaded by another thread. Load it again.
+        }
+
+        Err(Error::MapNotLoaded(primary.fname.clone()).into())
+    }
+
+    // Find the map with the same resolution as the primary map containing
+    // coordinate c. The primary map is preferred.
+    fn neighbour(&self, primary: &Arc<Map>, c: &Coord) -> Option<Arc<Map>> {
+        if primary.contains(c) {
+            return Some(Arc::clone(primary));
+        }
+
+        self.maps_at(c).into_iter()
+            .find(|m| m.same_grid(primary) && m.contains(c))
+    }
+}
+
+// Error of a single point of a batch lookup
+fn point_error(e: &(dyn std::error::Error + Send + Sync + 'static)) -> Error {
+    match e.downcast_ref::<Error>() {
+        Some(e) => e.clone(),
+        None => Error::Generic(e.to_string()),
+    }
+}
+
+// Check if the error is a NoData error
+fn is_nodata(e: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    matches!(e.downcast_ref::<Error>(), Some(Error::NoData(..)))
 }
 
 impl Serialize for Atlas {
@@ -315,23 +1265,12 @@ impl Serialize for Atlas {
     where
         S: Serializer,
     {
-	// Hash up maps on fname and serialize as a sequence
-	let mut distinct = HashMap::new();
-
-        for mv in self.maps.values() {
-	    for m in mv.iter() {
-		let name = m.fname.clone();
-		if distinct.contains_key(&name) {
-		    continue;
-		}
-		distinct.insert(name, m);
-	    }
-        }
+	// Serialize each distinct map as a sequence
+	let distinct = self.distinct_maps();
 
 	let mut seq = serializer.serialize_seq(Some(distinct.len()))?;
-	// Serialize this with distinct.serialize()?
-	for m in distinct.values() {
-	    seq.serialize_element(m)?;
+	for m in distinct.iter() {
+	    seq.serialize_element(m.as_ref())?;
 	}
 
         seq.end()
@@ -341,7 +1280,7 @@ impl Serialize for Atlas {
 struct VecMapDeserializer;
 
 impl<'de> Visitor<'de> for VecMapDeserializer {
-    type Value = Vec<Rc<Map>>;
+    type Value = Vec<Arc<Map>>;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         formatter.write_str("GeoTIFF map file.")
@@ -353,8 +1292,12 @@ impl<'de> Visitor<'de> for VecMapDeserializer {
     {
         let mut v = Vec::new();
 
-        while let Some(m) = seq.next_element::<Map>()? {
-	    v.push(Rc::new(m));
+        while let Some(mut m) = seq.next_element::<Map>()? {
+	    // Index files from older versions have no survey
+	    if m.survey == SurveyInfo::default() {
+		m.survey = SurveyInfo::new(&m.fname, &m.zipfile, None, None);
+	    }
+	    v.push(Arc::new(m));
         }
 
         Ok(v)
@@ -368,21 +1311,612 @@ impl<'de> Deserialize<'de> for Atlas {
     {
 	let v = deserializer.deserialize_seq(VecMapDeserializer)?;
 
-	let mut maps = HashMap::new();
+	let mut s = Atlas::new_empty(None);
+	s.maps = RwLock::new(MapIndex::new(v));
 
-	for m in v {
-	    for h in m.hashes() {
-		if !maps.contains_key(&h) {
-		    maps.insert(h, Vec::new());
-		}
-		maps.get_mut(&h).unwrap().push(Rc::clone(&m));
-	    }
-	}
+	Ok(s)
+    }
+}
 
-	Ok(Atlas {
-            maps: maps,
-            mockup: false,
-            tx: None,
-        })
+#[cfg(test)]
+impl Atlas {
+    // Create an atlas of the given maps, for testing without index files
+    pub(crate) fn from_maps(maps: Vec<Map>, tx: Option<MsgSender>) -> Self {
+        let a = Self::new_empty(tx);
+        for m in maps {
+            a.maps.write().unwrap().insert(Arc::new(m));
+        }
+
+        a
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::atlas::{Atlas, MsgSender, MAX_RELOADS};
+    use crate::cache::file_stamp;
+    use crate::catalogue::{Catalogue, CATALOGUE_FILE};
+    use crate::coord::Coord;
+    use crate::errors::Error;
+    use crate::indexfile;
+    use crate::interpolation::Interpolation;
+    use crate::map::Map;
+    use crate::mapfolder::MapFolder;
+    use crate::policy::OverlapPolicy;
+    use crate::survey::Product;
+    use crate::testdir::TestDir;
+    use crate::testmaps::{cache_dir, cached_map, flat_map, survey_map};
+    use std::path::Path;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    // Atlas of two 10x10 maps side by side, sampling the plane
+    // h = 0.5*e + 0.25*n with 10 m resolution
+    fn two_maps() -> Atlas {
+        let mut a = Atlas::new_empty(None);
+        let delta = Coord::new(10.0, 10.0);
+
+        for (i, e0) in [100000.0, 100100.0].iter().enumerate() {
+            let nw = Coord::new(*e0, 6800100.0);
+            let mut samples = Vec::new();
+            for y in 0..10 {
+                for x in 0..10 {
+                    let e = e0 + (x as f64 + 0.5)*10.0 - 100000.0;
+                    let n = 100.0 - (y as f64 + 0.5)*10.0;
+                    samples.push((0.5*e + 0.25*n) as f32);
+                }
+            }
+
+            a.maps.get_mut().unwrap().insert(Arc::new(Map::from_samples(
+                &format!("map{}", i), nw, delta, 10, samples)));
+        }
+
+        a
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn atlas_is_send_sync() {
+        assert_send_sync::<Atlas>();
+    }
+
+    #[test]
+    fn flush_empty() {
+        let a = Atlas::new_mockup();
+        assert_eq!(a.flush_maps(), 0);
+        assert_eq!(a.memory_usage().maps, 0);
+        assert_eq!(a.memory_usage().bytes, 0);
+    }
+
+    #[test]
+    fn unload_area() {
+        // Two loaded maps side by side
+        let a = two_maps();
+        let usage = a.memory_usage();
+        assert_eq!(usage.maps, 2);
+        let map0 = &a.maps_at(&Coord::new(100050.0, 6800050.0))[0];
+        let map1 = &a.maps_at(&Coord::new(100150.0, 6800050.0))[0];
+        let size = map0.image_bytes();
+
+        assert_eq!(a.unload_area(&Coord::new(100010.0, 6800090.0),
+                                 &Coord::new(100090.0, 6800010.0)), 1);
+        assert!(!map0.is_resident());
+        assert!(map1.is_loaded());
+        assert_eq!(a.memory_usage().maps, 1);
+        assert_eq!(a.memory_usage().bytes, usage.bytes - size);
+    }
+
+    #[test]
+    fn mockup_resolution() {
+        let a = Atlas::new_mockup();
+        let c = Coord::from("N6851889.09E146005.17");
+        let (h, res) = a.lookup_with_resolution(&c).unwrap();
+        assert_eq!(h, a.lookup(&c).unwrap());
+        assert_eq!(res, 1.0);
+    }
+
+    #[test]
+    fn lookup_batch() {
+        let a = Atlas::new_mockup();
+        let coords = [Coord::from("N6851889.09E146005.17"),
+                      Coord::from("N6922988.3E203182.98")];
+
+        let r = a.lookup_batch(&coords).unwrap();
+        assert_eq!(r.len(), 2);
+        assert_eq!(r[0], Ok(a.lookup(&coords[0]).unwrap()));
+        assert_eq!(r[1], Ok(a.lookup(&coords[1]).unwrap()));
+
+        let mut out = [0.0; 2];
+        assert_eq!(a.lookup_batch_into(&coords, &mut out).unwrap(), 0);
+        assert_eq!(out[1], a.lookup(&coords[1]).unwrap());
+        assert!(a.lookup_batch_into(&coords, &mut [0.0; 3]).is_err());
+    }
+
+    #[test]
+    fn lookup_batch_load_failure() {
+        // The image of map1 can not be loaded, as the map directory is not
+        // set
+        let mut a = two_maps();
+        a.set_map_folder(Arc::new(MapFolder::default()));
+        assert!(a.unload_map("map1"));
+
+        let coords = [Coord::new(100045.0, 6800055.0),
+                      Coord::new(100145.0, 6800055.0),
+                      Coord::new(100098.0, 6800055.0),
+                      Coord::new(100055.0, 6800045.0)];
+        let r = a.lookup_batch(&coords).unwrap();
+        assert_eq!(r[0], Ok(0.5*45.0 + 0.25*55.0));
+        assert_eq!(r[1], Err(Error::MapDirNotSet));
+        // On the seam, the samples of map1 are needed
+        assert_eq!(r[2], Err(Error::MapDirNotSet));
+        assert_eq!(r[3], Ok(0.5*55.0 + 0.25*45.0));
+    }
+
+    #[test]
+    fn lookup_batch_seam() {
+        // Two fine maps over a coarse map covering both
+        let mut a = two_maps();
+        a.set_interpolation(Interpolation::Bilinear);
+        a.maps.get_mut().unwrap().insert(Arc::new(flat_map(
+            "coarse", Coord::new(100000.0, 6800100.0), 20.0, 10, 1000.0)));
+
+        // On the seam, the fine maps are stitched rather than falling back
+        // to the coarse map
+        let coords = [Coord::new(100098.0, 6800055.0),
+                      Coord::new(100045.0, 6800055.0)];
+        let r = a.lookup_batch(&coords).unwrap();
+        for (c, r) in coords.iter().zip(&r) {
+            assert_eq!(*r, Ok(a.lookup(c).unwrap()));
+        }
+        assert_eq!(r[0], Ok(0.5*98.0 + 0.25*55.0));
+    }
+
+    #[test]
+    fn zip_file() {
+        // testdata/plane.zip holds testdata/plane.tif, a 10x10 map with 10 m
+        // pixels and height 100 + x + 10*y at pixel (x, y)
+        let folder = Arc::new(MapFolder::new("testdata/"));
+        let z = Atlas::new_from_zip_file(&folder, "plane.zip", None).unwrap();
+        let maps = z.distinct_maps();
+        assert_eq!(maps.len(), 1);
+        assert_eq!(maps[0].fname, "plane.zip.dir/plane.tif");
+        assert_eq!(maps[0].zipfile, "plane.zip");
+
+        // The zipped map is indexed and looked up as the plain file
+        let m = Map::new(&folder, "plane.tif", "", None).unwrap();
+        assert_eq!((maps[0].nw, maps[0].se, maps[0].delta),
+                   (m.nw, m.se, m.delta));
+        assert_eq!((maps[0].crs, maps[0].nodata), (Some(25833), m.nodata));
+
+        let mut a = Atlas::new_empty(None);
+        a.set_map_folder(Arc::clone(&folder));
+        a.maps.get_mut().unwrap().insert(Arc::new(m));
+
+        for (x, y) in [(1, 1), (3, 7), (8, 8)] {
+            let c = Coord::new(100005.0 + 10.0*x as f64,
+                               6800095.0 - 10.0*y as f64);
+            let h = (100 + x + 10*y) as f32;
+            assert_eq!(z.lookup(&c).unwrap(), h);
+            assert_eq!(a.lookup(&c).unwrap(), h);
+        }
+    }
+
+    #[test]
+    fn not_zip_file() {
+        let folder = Arc::new(MapFolder::new("testdata/"));
+        let err = Atlas::new_from_zip_file(&folder, "6700_4_10m_z33.tif", None)
+            .err().unwrap();
+        assert_eq!(err.downcast_ref::<Error>(),
+                   Some(&Error::NotZipFile(String::from("6700_4_10m_z33.tif"))));
+    }
+
+    #[test]
+    fn prefetch_failure() {
+        // The map can not be loaded, and the message receiver is gone
+        let (tx, rx) = crossbeam_channel::unbounded();
+        drop(rx);
+        let mut a = Atlas::new_empty(Some(tx));
+        a.set_map_folder(Arc::new(MapFolder::default()));
+        let m = Arc::new(flat_map("m", Coord::new(0.0, 100.0), 10.0, 10, 0.0));
+        m.unload_image();
+
+        a.prefetch_maps(&[Arc::clone(&m)]);
+        assert!(!m.is_loaded());
+    }
+
+    // Atlas of unloaded 10x10 maps of height i side by side, loading from
+    // the raster cache in dir
+    fn cached_maps(dir: &TestDir, n: usize, tx: Option<MsgSender>) -> Atlas {
+        let maps = (0..n).map(|i| {
+            let nw = Coord::new(100000.0 + 100.0*i as f64, 6800100.0);
+            cached_map(dir, &format!("m{}.tif", i), nw, 10.0, 10, i as f32)
+        }).collect();
+        let mut a = Atlas::from_maps(maps, tx);
+        a.set_map_folder(Arc::new(MapFolder::new(&dir.map_dir())));
+        a.set_cache_dir(Some(&cache_dir(dir)));
+        a
+    }
+
+    #[test]
+    fn memory_budget() {
+        let dir = TestDir::new("budget");
+        let mut a = cached_maps(&dir, 3, None);
+        let c = |i: usize| Coord::new(100050.0 + 100.0*i as f64, 6800050.0);
+
+        // Room for two of the three maps
+        assert_eq!(a.lookup(&c(0)).unwrap(), 0.0);
+        let size = a.memory_usage().bytes;
+        a.set_memory_budget(Some(2*size));
+
+        for (i, evictions) in [(1, 0), (2, 1), (0, 2)] {
+            assert_eq!(a.lookup(&c(i)).unwrap(), i as f32);
+            assert!(a.memory_usage().bytes <= 2*size);
+            assert_eq!(a.evictions(), evictions);
+        }
+
+        // Map 0 was evicted when loading map 2, and map 1 when loading map 0
+        // again
+        let loaded: Vec<bool> = (0..3)
+            .map(|i| a.lookup_maps(&c(i)).unwrap()[0].is_loaded())
+            .collect();
+        assert_eq!(loaded, vec![true, false, true]);
+        assert_eq!(a.memory_usage().maps, 2);
+    }
+
+    #[test]
+    fn bounded_reloads() {
+        // A lookup that always finds the map evicted gives up
+        let a = two_maps();
+        let c = Coord::new(100045.0, 6800055.0);
+        let m = &a.maps_at(&c)[0];
+        let calls = std::cell::Cell::new(0);
+
+        let r: crate::errors::Result<()> = a.lookup_map(m, &[c], |m| {
+            calls.set(calls.get() + 1);
+            Err(Error::MapNotLoaded(m.fname.clone()).into())
+        });
+        assert!(matches!(r.unwrap_err().downcast_ref::<Error>(),
+                         Some(Error::MapNotLoaded(_))));
+        assert_eq!(calls.get(), MAX_RELOADS + 2);
+    }
+
+    #[test]
+    fn lookup_at_seam() {
+        let mut a = two_maps();
+
+        for interpolation in [Interpolation::Nearest, Interpolation::Bilinear,
+                              Interpolation::Bicubic] {
+            a.set_interpolation(interpolation);
+
+            // Just west and east of the seam between the maps
+            for e in [100098.0, 100102.0] {
+                let c = Coord::new(e, 6800050.0);
+                let (h, dx, dy) = a.lookup_with_gradient(&c).unwrap();
+                if interpolation != Interpolation::Nearest {
+                    let expected = (0.5*(e - 100000.0) + 12.5) as f32;
+                    assert!((h - expected).abs() < 1e-3);
+                }
+                assert!((dx - 0.5).abs() < 1e-4);
+                assert!((dy - 0.25).abs() < 1e-4);
+            }
+        }
+
+        // Outer edge of the atlas
+        let c = Coord::new(100001.0, 6800050.0);
+        assert!(a.lookup(&c).is_err());
+    }
+
+    #[test]
+    fn map_folders() {
+        // Two data sets in separate folders, with maps at different places
+        let mut folders = Vec::new();
+        for e in [100000.0, 200000.0] {
+            let dir = TestDir::new("folder");
+            let m = flat_map("m", Coord::new(e, 6800100.0), 10.0, 10, 1.0);
+            indexfile::write_index(&dir.file("atlas.bin"), [&m]).unwrap();
+            let folder = Arc::new(MapFolder::new(&dir.map_dir()));
+            folders.push((dir, folder));
+        }
+
+        let a = Atlas::new_in_folder(Arc::clone(&folders[0].1), &[10.0], None)
+            .unwrap();
+        let b = Atlas::new_in_folder(Arc::clone(&folders[1].1), &[10.0], None)
+            .unwrap();
+        assert!(Arc::ptr_eq(a.map_folder(), &folders[0].1));
+        assert!(a.has_maps(&Coord::new(100050.0, 6800050.0)));
+        assert!(!a.has_maps(&Coord::new(200050.0, 6800050.0)));
+        assert!(b.has_maps(&Coord::new(200050.0, 6800050.0)));
+        assert!(!b.has_maps(&Coord::new(100050.0, 6800050.0)));
+
+        let err = Atlas::new_in_folder(Arc::new(MapFolder::default()), &[10.0],
+                                       None).err().unwrap();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::MapDirNotSet));
+    }
+
+    #[test]
+    fn lazy_index_files() {
+        // Two index files with a map each, far apart
+        let dir = TestDir::new("lazy");
+        for (file, e) in [("a.atlas.bin", 100000.0),
+                          ("b.atlas.bin", 200000.0)] {
+            let m = flat_map("m", Coord::new(e, 6800100.0), 10.0, 10, 1.0);
+            indexfile::write_index(&dir.file(file), [&m]).unwrap();
+        }
+
+        let folder = Arc::new(MapFolder::new(&dir.map_dir()));
+        let a = Atlas::new_in_folder(folder, &[10.0], None).unwrap();
+        assert_eq!(a.pending.read().unwrap().len(), 2);
+        assert!(a.distinct_maps().is_empty());
+
+        // Only the index file touched by the query is read
+        assert!(a.has_maps(&Coord::new(100050.0, 6800050.0)));
+        let pending: Vec<_> = a.pending.read().unwrap().iter()
+            .map(|e| e.file.clone()).collect();
+        assert_eq!(pending, vec!["b.atlas.bin"]);
+        assert_eq!(a.distinct_maps().len(), 1);
+    }
+
+    #[test]
+    fn catalogue_rebuild() {
+        let dir = TestDir::new("catalogue");
+        let index = dir.file("a.atlas.bin");
+        let folder = Arc::new(MapFolder::new(&dir.map_dir()));
+        let map = |e| flat_map("m", Coord::new(e, 6800100.0), 10.0, 10, 1.0);
+
+        indexfile::write_index(&index, [&map(100000.0)]).unwrap();
+        Atlas::new_in_folder(Arc::clone(&folder), &[10.0], None).unwrap();
+        let c = Catalogue::read(&dir.file(CATALOGUE_FILE)).unwrap();
+        assert_eq!(c.entries.len(), 1);
+        assert_eq!(c.entries[0].maps, 1);
+
+        // A changed index file is summarized again
+        let maps = [map(100000.0), map(100100.0)];
+        indexfile::write_index(&index, maps.iter()).unwrap();
+        let stamp = file_stamp(Path::new(&index)).unwrap();
+        assert!(c.get("a.atlas.bin", stamp).is_none());
+
+        let a = Atlas::new_in_folder(folder, &[10.0], None).unwrap();
+        let c = Catalogue::read(&dir.file(CATALOGUE_FILE)).unwrap();
+        let e = c.get("a.atlas.bin", stamp).unwrap();
+        assert_eq!(e.maps, 2);
+        assert_eq!(e.se, Coord::new(100200.0, 6800000.0));
+        assert!(a.has_maps(&Coord::new(100150.0, 6800050.0)));
+    }
+
+    #[test]
+    fn migrate_json_index() {
+        let dir = TestDir::new("atlas");
+        let json = &dir.file("maps.zip.atlas.json");
+        std::fs::write(json, serde_json::to_string(&two_maps()).unwrap())
+            .unwrap();
+
+        let a = Atlas::read_or_migrate(json, None).unwrap();
+        assert_eq!(a.distinct_maps().len(), 2);
+
+        let a = Atlas::read_atlas(&format!("{}.bin",
+                                           json.strip_suffix(".json")
+                                           .unwrap())).unwrap();
+        let names: Vec<_> = a.distinct_maps().iter()
+            .map(|m| m.fname.clone()).collect();
+        assert_eq!(names, vec!["map0", "map1"]);
+        assert!(a.has_maps(&Coord::new(100150.0, 6800050.0)));
+    }
+
+    #[test]
+    fn crs() {
+        let dir = TestDir::new("crs");
+        let file = &dir.file("maps.atlas.bin");
+
+        let a = two_maps();
+        assert_eq!(a.crs().unwrap(), None);
+
+        let mut maps: Vec<Map> = a.distinct_maps().iter()
+            .map(|m| flat_map(&m.fname, m.nw, 10.0, 10, 0.0))
+            .collect();
+        maps[0].crs = Some(25833);
+        indexfile::write_index(file, maps.iter()).unwrap();
+        assert_eq!(Atlas::read_atlas(file).unwrap().crs().unwrap(),
+                   Some(25833));
+
+        // Maps in another CRS are not mixed in
+        maps[1].crs = Some(25832);
+        indexfile::write_index(file, maps.iter()).unwrap();
+        let err = Atlas::read_atlas(file).err().unwrap();
+        assert!(matches!(err.downcast_ref::<Error>(),
+                         Some(Error::CrsMismatch(_, 25832, 25833))));
+    }
+
+    #[test]
+    fn overlap_policy() {
+        // A fine and a coarse map over the same area, from different surveys
+        let mut a = Atlas::new_empty(None);
+        let nw = Coord::new(100000.0, 6800100.0);
+        for (fname, zipfile, delta, width, h) in
+            [("fine", "NDH Test 2pkt 2015.zip", 10.0, 10, 1.0),
+             ("coarse", "NDH Test 1pkt 2019.zip", 20.0, 5, 3.0)] {
+            a.maps.get_mut().unwrap().insert(Arc::new(
+                survey_map(fname, zipfile, nw, delta, width, h)));
+        }
+
+        let c = Coord::new(100050.0, 6800050.0);
+        assert_eq!(a.lookup_with_resolution(&c).unwrap(), (1.0, 10.0));
+
+        let surveys = a.surveys(&c).unwrap();
+        assert_eq!(surveys.len(), 2);
+        assert_eq!(surveys[0].project, "NDH Test 2pkt 2015");
+        assert_eq!(surveys[0].year, Some(2015));
+        assert_eq!(surveys[0].product, Product::Unknown);
+        assert!(a.surveys(&Coord::new(0.0, 0.0)).is_err());
+
+        for (policy, expected) in
+            [(OverlapPolicy::NewestSurvey, (3.0, 20.0)),
+             (OverlapPolicy::PreferProject("NDH Test 1pkt 2019".to_string()),
+              (3.0, 20.0)),
+             (OverlapPolicy::Average, (2.0, 10.0))] {
+            a.set_overlap_policy(policy);
+            assert_eq!(a.lookup_with_resolution(&c).unwrap(), expected);
+            assert_eq!(a.lookup_batch(&[c]).unwrap()[0], Ok(expected.0));
+            assert_eq!(a.lookup_with_gradient(&c).unwrap().0, expected.0);
+        }
+    }
+
+    #[test]
+    fn layered_fallback() {
+        // A 10 m layer covering the west half of a 20 m layer
+        let mut a = Atlas::new_empty(None);
+        let nw = Coord::new(100000.0, 6800100.0);
+        for m in [flat_map("fine", nw, 10.0, 10, 1.0),
+                  flat_map("coarse", nw, 20.0, 10, 3.0)] {
+            a.maps.get_mut().unwrap().insert(Arc::new(m));
+        }
+
+        let c = Coord::new(100055.0, 6800045.0);
+        assert_eq!(a.lookup_with_resolution(&c).unwrap(), (1.0, 10.0));
+
+        // Outside of the fine layer, and on its edge where the neighbours
+        // needed are missing
+        for c in [Coord::new(100150.0, 6800050.0),
+                  Coord::new(100095.0, 6800050.0)] {
+            assert_eq!(a.lookup_with_resolution(&c).unwrap(), (3.0, 20.0));
+            let info = a.lookup_with_provenance(&c).unwrap();
+            assert_eq!((info.fname.as_str(), info.resolution),
+                       ("coarse", 20.0));
+            assert_eq!(a.lookup_batch(&[c]).unwrap()[0], Ok(3.0));
+        }
+    }
+
+    #[test]
+    fn lookup_with_provenance() {
+        let a = two_maps();
+        let c = Coord::new(100123.0, 6800047.0);
+        let info = a.lookup_with_provenance(&c).unwrap();
+
+        assert_eq!(info.height, a.lookup(&c).unwrap());
+        assert_eq!(info.fname, "map1");
+        assert_eq!(info.pixel, (2, 5));
+        assert_eq!(info.centre, Coord::new(100125.0, 6800045.0));
+        assert_eq!(info.resolution, 10.0);
+        assert_eq!(info.interpolation, Interpolation::Nearest);
+        assert_eq!(info.maps, 1);
+
+        assert!(a.lookup_with_provenance(&Coord::new(0.0, 0.0)).is_err());
+    }
+
+    #[test]
+    fn nodata() {
+        // A fine map with a void, over a coarse map
+        let mut a = Atlas::new_empty(None);
+        let nw = Coord::new(100000.0, 6800100.0);
+        let mut samples = vec![1.0; 100];
+        samples[55] = -32767.0;
+        let mut fine = Map::from_samples("fine", nw, Coord::new(10.0, 10.0),
+                                         10, samples);
+        fine.nodata = Some(-32767.0);
+        a.maps.get_mut().unwrap().insert(Arc::new(fine));
+
+        let c = Coord::new(100055.0, 6800045.0);
+        let e = a.lookup(&c).unwrap_err();
+        assert!(matches!(e.downcast_ref::<Error>(), Some(Error::NoData(..))));
+        assert!(matches!(a.lookup_batch(&[c]).unwrap()[0],
+                         Err(Error::NoData(..))));
+        assert_eq!(a.lookup(&Coord::new(100025.0, 6800075.0)).unwrap(), 1.0);
+
+        a.maps.get_mut().unwrap().insert(Arc::new(
+            flat_map("coarse", nw, 20.0, 5, 3.0)));
+        assert_eq!(a.lookup_with_resolution(&c).unwrap(), (3.0, 20.0));
+        assert_eq!(a.lookup_batch(&[c]).unwrap()[0], Ok(3.0));
+    }
+
+    #[test]
+    fn maps_in_area() {
+        let a = two_maps();
+        let m = a.maps_in_area(&Coord::new(100050.0, 6800050.0),
+                               &Coord::new(100060.0, 6800040.0));
+        assert_eq!(m.len(), 1);
+        assert_eq!(m[0].fname, "map0");
+
+        let m = a.maps_in_area(&Coord::new(99000.0, 6801000.0),
+                               &Coord::new(101000.0, 6799000.0));
+        assert_eq!(m.len(), 2);
+
+        let m = a.maps_in_area(&Coord::new(101000.0, 6801000.0),
+                               &Coord::new(102000.0, 6800000.0));
+        assert!(m.is_empty());
+    }
+
+    #[test]
+    fn maps_along() {
+        let a = two_maps();
+        let m = a.maps_along(&[Coord::new(100150.0, 6800050.0),
+                               Coord::new(100050.0, 6800050.0)]);
+        let names: Vec<_> = m.iter().map(|m| m.fname.as_str()).collect();
+        assert_eq!(names, vec!["map1", "map0"]);
+    }
+
+    #[test]
+    fn preload() {
+        // The maps of the test atlas are already loaded
+        let a = two_maps();
+        let s = a.preload_polyline(&[Coord::new(100150.0, 6800150.0),
+                                     Coord::new(100150.0, 6800120.0)],
+                                   25.0).unwrap();
+        assert_eq!(s.maps, 1);
+        assert_eq!(s.loaded, 0);
+        assert_eq!(s.bytes, 400);
+
+        let s = a.preload_polygon(&[Coord::new(100050.0, 6800050.0),
+                                    Coord::new(100150.0, 6800050.0),
+                                    Coord::new(100100.0, 6800080.0)]).unwrap();
+        assert_eq!(s.maps, 2);
+
+        let s = a.preload_area(&Coord::new(100120.0, 6800050.0),
+                               &Coord::new(100130.0, 6800040.0)).unwrap();
+        assert_eq!(s.maps, 1);
+    }
+
+    #[test]
+    fn concurrent_lookup() {
+        let a = Arc::new(Atlas::new_mockup());
+        let c = Coord::from("N6851889.09E146005.17");
+        let expected = a.lookup(&c).unwrap();
+
+        let handles: Vec<_> = (0..4).map(|_| {
+            let a = Arc::clone(&a);
+            thread::spawn(move || a.lookup(&c).unwrap())
+        }).collect();
+
+        for h in handles {
+            assert_eq!(h.join().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn concurrent_load() {
+        // An unloaded map looked up by several threads at once is loaded by
+        // one of them
+        let dir = TestDir::new("concurrent");
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let a = Arc::new(cached_maps(&dir, 1, Some(tx)));
+        let c = Coord::new(100050.0, 6800050.0);
+        let barrier = Arc::new(Barrier::new(8));
+
+        let handles: Vec<_> = (0..8).map(|_| {
+            let a = Arc::clone(&a);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                a.lookup(&c).unwrap()
+            })
+        }).collect();
+
+        for h in handles {
+            assert_eq!(h.join().unwrap(), 0.0);
+        }
+
+        let loads = rx.try_iter()
+            .filter(|msg| msg.starts_with("Mapped cached file"))
+            .count();
+        assert_eq!(loads, 1);
     }
 }
@@ -1,5 +1,6 @@
-use hoydedata::{unmount_all_maps, Atlas, Result};
+use hoydedata::{Atlas, MapFolder, Result};
 use std::env;
+use std::sync::Arc;
 
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
@@ -9,23 +10,22 @@ fn main() -> Result<()> {
         dir.push_str("/");
     }
 
+    let folder = Arc::new(MapFolder::new(&dir));
     let file = &args[3];
     let afile;
     let a;
 
     if file == "" {
 	// No file. Index directory.
-	a = Atlas::new_from_directory("", "", None)?;
-	afile = format!("{}{}", dir, "atlas.json");
+	a = Atlas::new_from_directory(&folder, "", "", None)?;
+	afile = format!("{}{}", dir, "atlas.bin");
     }
     else {
-	a = Atlas::new_from_zip_file(&file, None)?;
-	afile = format!("{}{}{}", dir, file, ".atlas.json");
+	a = Atlas::new_from_zip_file(&folder, &file, None)?;
+	afile = format!("{}{}{}", dir, file, ".atlas.bin");
     }
 
     a.write_atlas(&afile)?;
 
-    unmount_all_maps();
-
     Ok(())
 }
@@ -1,6 +1,6 @@
 extern crate hoydedata;
 
-use hoydedata::{Atlas, Coord, Result, set_map_dir, unmount_all_maps};
+use hoydedata::{Atlas, Coord, Result, set_map_dir};
 
 use std::env;
 
@@ -15,13 +15,14 @@ fn main() -> Result<()> {
     println!("Coordinate is {}", c);
     
     for m in a.lookup_maps(&c)? {
-	println!("Map: {}", m.fname);
+	println!("Map: {} (project '{}', year {:?}, {:?})", m.fname,
+		 m.survey.project, m.survey.year, m.survey.product);
     }
 
-    let height = a.lookup(&c)?;
-    println!("Height: {}", height);
-    
-    unmount_all_maps();
-    
+    let info = a.lookup_with_provenance(&c)?;
+    println!("Height: {}", info.height);
+    println!("From pixel {:?} (centre {}) of {} at {} m resolution",
+	     info.pixel, info.centre, info.fname, info.resolution);
+
     Ok(())
 }
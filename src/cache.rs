@@ -0,0 +1,147 @@
+use crate::errors::*;
+use crate::raster::Samples;
+
+use memmap2::Mmap;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::UNIX_EPOCH;
+
+const MAGIC: &[u8; 4] = b"HDRC";
+const VERSION: u32 = 1;
+// Magic, version, width, height, source size and source mtime. The length
+// is a multiple of four, so that the samples are aligned in the mapping.
+const HEADER_LEN: usize = 4 + 4 + 8*4;
+
+// Counter making the temporary files of this process unique
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Size and modification time (in nanoseconds) of a file
+pub fn file_stamp(path: &Path) -> Result<(u64, u64)> {
+    let meta = fs::metadata(path)?;
+    let mtime = meta.modified()?.duration_since(UNIX_EPOCH)?.as_nanos();
+
+    Ok((meta.len(), mtime as u64))
+}
+
+// Temporary file to write before renaming it to path, so that readers never
+// see a partly written file. It is unique to the writer, as other threads and
+// processes may be writing the same file.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}-{}.tmp", std::process::id(),
+                      TMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+    PathBuf::from(name)
+}
+
+/*
+Cache of decoded map images. Each map is stored as a raw file of native
+endian f32 samples, following a header identifying the map size and the size
+and modification time of the source file. Cached images are memory mapped
+instead of being decoded again. A cache file is ignored, and later
+overwritten, when the source file changes.
+ */
+pub struct RasterCache {
+    dir: PathBuf,
+}
+
+impl RasterCache {
+    pub fn new(dir: &str) -> Self {
+        Self {
+            dir: PathBuf::from(dir),
+        }
+    }
+
+    // Cache file for map file fname
+    fn path(&self, fname: &str) -> PathBuf {
+        self.dir.join(format!("{}.raw", fname))
+    }
+
+    fn header(width: usize, height: usize, stamp: (u64, u64)) -> Vec<u8> {
+        let mut h = Vec::with_capacity(HEADER_LEN);
+        h.extend_from_slice(MAGIC);
+        h.extend_from_slice(&VERSION.to_ne_bytes());
+        for v in [width as u64, height as u64, stamp.0, stamp.1] {
+            h.extend_from_slice(&v.to_ne_bytes());
+        }
+
+        h
+    }
+
+    /*
+    Map the cached image of map file fname, read from the given source file.
+    Returns None if the image is not cached, or the cache file is stale.
+     */
+    pub fn read(&self, fname: &str, source: &Path, width: usize,
+                height: usize) -> Result<Option<Samples>> {
+        let Ok(file) = fs::File::open(self.path(fname)) else {
+            return Ok(None);
+        };
+
+        // The cache file is only written by us, and is replaced rather than
+        // modified, so the mapping stays valid while in use.
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let expected = Self::header(width, height, file_stamp(source)?);
+        if mmap.len() != HEADER_LEN + width*height*4 ||
+            mmap[..HEADER_LEN] != expected[..] {
+            return Ok(None);
+        }
+
+        Ok(Some(Samples::Mapped(mmap, HEADER_LEN)))
+    }
+
+    // Store the image of map file fname, read from the given source file
+    pub fn write(&self, fname: &str, source: &Path, width: usize,
+                 height: usize, samples: &[f32]) -> Result<()> {
+        let path = self.path(fname);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let tmp = temp_path(&path);
+        let mut file = BufWriter::new(fs::File::create(&tmp)?);
+        file.write_all(&Self::header(width, height, file_stamp(source)?))?;
+        for v in samples {
+            file.write_all(&v.to_ne_bytes())?;
+        }
+        file.into_inner()?;
+        fs::rename(&tmp, &path)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::{RasterCache, temp_path};
+    use crate::testdir::TestDir;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn write_and_read() {
+        let dir = TestDir::new("cache");
+        let source = dir.path().join("source.tif");
+        fs::write(&source, b"source").unwrap();
+
+        let cache = RasterCache::new(dir.path().to_str().unwrap());
+        assert!(cache.read("a/m.tif", &source, 2, 2).unwrap().is_none());
+
+        cache.write("a/m.tif", &source, 2, 2, &[1.0, 2.0, 3.0, 4.0]).unwrap();
+        let s = cache.read("a/m.tif", &source, 2, 2).unwrap().unwrap();
+        assert_eq!(s.as_slice(), Some(&[1.0, 2.0, 3.0, 4.0][..]));
+
+        // Wrong size
+        assert!(cache.read("a/m.tif", &source, 4, 1).unwrap().is_none());
+
+        // Source file changed
+        fs::write(&source, b"changed source").unwrap();
+        assert!(cache.read("a/m.tif", &source, 2, 2).unwrap().is_none());
+        assert!(cache.read("a/m.tif", Path::new("/nonexistent"), 2, 2).is_err());
+
+        let file = dir.path().join("m.tif");
+        assert_ne!(temp_path(&file), temp_path(&file));
+    }
+}
@@ -0,0 +1,143 @@
+use crate::errors::*;
+use crate::coord::Coord;
+use crate::indexfile::{self, IndexReader, IndexWriter};
+
+use std::fs;
+
+const MAGIC: &[u8; 4] = b"HDAC";
+// Version of the catalogue record layout. Bump when the records change.
+// Catalogues with older versions are rebuilt.
+const SCHEMA_VERSION: u32 = 2;
+
+// Name of the catalogue file in the map directory
+pub const CATALOGUE_FILE: &str = "catalogue.bin";
+
+// Summary of an index file
+#[derive(Clone, Debug, PartialEq)]
+pub struct CatalogueEntry {
+    // Name of the index file within the map directory
+    pub file: String,
+    // Size and modification time of the index file when it was summarized
+    pub stamp: (u64, u64),
+    pub resolution: f32,
+    // Corners of the bounding box of the maps of the index file
+    pub nw: Coord,
+    pub se: Coord,
+    pub maps: usize,
+    // EPSG code of the CRS of the maps, if known
+    pub crs: Option<u32>,
+}
+
+impl CatalogueEntry {
+    // Check if the bounding box overlaps the area between the corners nw
+    // and se
+    pub fn intersects(&self, nw: &Coord, se: &Coord) -> bool {
+        self.maps > 0 &&
+            self.nw.e <= se.e && self.se.e >= nw.e &&
+            self.se.n <= nw.n && self.nw.n >= se.n
+    }
+
+    fn encode(&self, w: &mut IndexWriter) {
+        w.str(&self.file);
+        w.u64(self.stamp.0);
+        w.u64(self.stamp.1);
+        w.f32(self.resolution);
+        w.coord(&self.nw);
+        w.coord(&self.se);
+        w.u64(self.maps as u64);
+        w.u64(self.crs.unwrap_or(0) as u64);
+    }
+
+    fn decode(r: &mut IndexReader) -> Result<Self> {
+        Ok(Self {
+            file: r.str()?,
+            stamp: (r.u64()?, r.u64()?),
+            resolution: r.f32()?,
+            nw: r.coord()?,
+            se: r.coord()?,
+            maps: r.u64()? as usize,
+            crs: Some(r.u64()? as u32).filter(|c| *c != 0),
+        })
+    }
+}
+
+/*
+Catalogue of the index files of a map directory. It lets an atlas find the
+index files it needs, and their extents, without reading all of them. An
+entry is only trusted while the size and modification time of its index
+file are unchanged.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Catalogue {
+    pub entries: Vec<CatalogueEntry>,
+}
+
+impl Catalogue {
+    pub fn read(file: &str) -> Result<Self> {
+        let data = fs::read(file)?;
+        let (version, count, mut r) = indexfile::read_records(
+            file, &data, MAGIC, SCHEMA_VERSION)?;
+        if version != SCHEMA_VERSION {
+            return Err(Error::InvalidIndex(
+                file.to_string(), String::from("Outdated catalogue")).into());
+        }
+
+        let mut entries = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            entries.push(CatalogueEntry::decode(&mut r)?);
+        }
+        r.finish()?;
+
+        Ok(Self { entries })
+    }
+
+    pub fn write(&self, file: &str) -> Result<()> {
+        let mut w = IndexWriter::default();
+        for e in self.entries.iter() {
+            e.encode(&mut w);
+        }
+
+        indexfile::write_records(file, MAGIC, SCHEMA_VERSION,
+                                 self.entries.len() as u64, &w)
+    }
+
+    // Entry of an index file, if it is still valid for the given stamp
+    pub fn get(&self, file: &str, stamp: (u64, u64))
+               -> Option<&CatalogueEntry> {
+        self.entries.iter().find(|e| e.file == file && e.stamp == stamp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::catalogue::*;
+    use crate::coord::Coord;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn write_and_read() {
+        let dir = TestDir::new("catalogue");
+        let file = &dir.file("catalogue.bin");
+
+        let entry = CatalogueEntry {
+            file: String::from("a.zip.atlas.bin"),
+            stamp: (100, 200),
+            resolution: 10.0,
+            nw: Coord::new(100000.0, 6800100.0),
+            se: Coord::new(100200.0, 6800000.0),
+            maps: 2,
+            crs: Some(25833),
+        };
+        let c = Catalogue { entries: vec![entry.clone()] };
+        c.write(file).unwrap();
+
+        let c = Catalogue::read(file).unwrap();
+        assert_eq!(c.get("a.zip.atlas.bin", (100, 200)), Some(&entry));
+        assert_eq!(c.get("a.zip.atlas.bin", (100, 201)), None);
+
+        assert!(entry.intersects(&Coord::new(100150.0, 6800050.0),
+                                 &Coord::new(100150.0, 6800050.0)));
+        assert!(!entry.intersects(&Coord::new(100250.0, 6800050.0),
+                                  &Coord::new(100300.0, 6800000.0)));
+    }
+}
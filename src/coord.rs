@@ -95,18 +95,20 @@ impl fmt::Display for Coord3 {
     }
 }
 
+// Easting and northing. Double precision is needed to resolve centimetres at
+// northings of several million metres.
 #[derive(Copy, Debug, Clone, PartialEq)]
 pub struct Coord {
-    pub e: f32,
-    pub n: f32,
+    pub e: f64,
+    pub n: f64,
 }
 
 impl Coord {
-    pub fn new(e: f32, n: f32) -> Coord {
+    pub fn new(e: f64, n: f64) -> Coord {
 	Coord { e: e, n: n }
     }
 
-    pub fn from_polar(r: f32, phi: f32) -> Coord {
+    pub fn from_polar(r: f64, phi: f64) -> Coord {
 	Coord { e: r*phi.cos(), n: r*phi.sin() }
     }
 
@@ -119,22 +121,19 @@ impl Coord {
         let (n, e, _) = to_utm_wgs84(lat, lon, 33);
         // FIXME: Are we guaranteed to get the right north (true), band (W)
         // and ups (false) for our norwegian coordinates?
-        Self {
-            e: e as f32,
-            n: n as f32,
-        }
+        Self { e, n }
     }
     
     // Absolute length from origo
-    pub fn abs(&self) -> f32 {
+    pub fn abs(&self) -> f64 {
 	(self.e*self.e + self.n*self.n).sqrt()
     }
 
-    pub fn abs_sq(&self) -> f32 {
+    pub fn abs_sq(&self) -> f64 {
 	self.e*self.e + self.n*self.n
     }
 
-    pub fn dot(&self, o: &Coord) -> f32 {
+    pub fn dot(&self, o: &Coord) -> f64 {
         self.e*o.e + self.n*o.n
     }
 
@@ -159,8 +158,7 @@ impl Coord {
 
     // Return longitude and latitude
     pub fn latlon(&self) -> (f64, f64) {
-        let utm = Utm::new(
-            self.e as f64, self.n as f64, true, 33, 'W', false);
+        let utm = Utm::new(self.e, self.n, true, 33, 'W', false);
         let gc : geomorph::Coord = utm.into();
         return (gc.lat, gc.lon);
     }
@@ -217,10 +215,10 @@ impl ops::Sub<Coord> for Coord {
     }
 }
 
-impl ops::Mul<f32> for Coord {
+impl ops::Mul<f64> for Coord {
     type Output = Coord;
 
-    fn mul(self, _rhs: f32) -> Coord {
+    fn mul(self, _rhs: f64) -> Coord {
 	Coord { e: self.e*_rhs, n: self.n*_rhs }
     }
 }
@@ -268,4 +266,14 @@ mod tests {
 	let f = format!("{}", c);
 	assert_eq!(f, "N-11.3E10.5");
     }
+
+    #[test]
+    fn precision() {
+	// Centimetres are kept at large northings, also through the text format
+	let c: Coord = "N7801561.74E796000.84".parse().unwrap();
+	assert_eq!(c.n, 7801561.74);
+	assert_eq!(c.e, 796000.84);
+	assert_eq!(c.to_string(), "N7801561.74E796000.84");
+	assert!(((c + Coord::new(0.0, 0.01)).n - c.n - 0.01).abs() < 1e-6);
+    }
 }
@@ -2,7 +2,7 @@ use std::error;
 use crate::coord::Coord;
 use thiserror::Error;
 
-pub type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+pub type Result<T> = std::result::Result<T, Box<dyn error::Error + Send + Sync>>;
 
 #[derive(Clone, Debug, Error, PartialEq)]
 pub enum Error {
@@ -10,8 +10,20 @@ pub enum Error {
     LookupError(Coord, String),
     #[error("No map for coordinate '{}'", .0)]
     MapNotFound(Coord),
+    #[error("No data at '{}' on map '{}'", .0, .1)]
+    NoData(Coord, String),
     #[error("Map not loaded '{}'", .0)]
     MapNotLoaded(String),
+    #[error("Invalid atlas index '{}': {}", .0, .1)]
+    InvalidIndex(String, String),
+    #[error("Map '{}' has CRS EPSG:{}, but the atlas has EPSG:{}. Reprojection is not supported.", .0, .1, .2)]
+    CrsMismatch(String, u32, u32),
+    #[error("Map '{}' has no invertible geotransform", .0)]
+    InvalidGeoTransform(String),
+    #[error("Map directory is not set")]
+    MapDirNotSet,
+    #[error("'{}' is not a zip file", .0)]
+    NotZipFile(String),
     #[error("Error: '{}'", .0)]
     Generic(String),
 }
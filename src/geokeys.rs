@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// GeoKey directory tag of GeoTIFF files
+pub const GEO_KEY_DIRECTORY_TAG: u16 = 0x87AF;
+
+const GT_RASTER_TYPE_GEO_KEY: u16 = 1025;
+const PROJECTED_CS_TYPE_GEO_KEY: u16 = 3072;
+// Value of keys defined by other keys rather than by a code
+const USER_DEFINED: u16 = 32767;
+
+/*
+Keys of a GeoTIFF GeoKey directory. The directory is an array of shorts,
+starting with a header of four shorts (version, revision, minor revision and
+number of keys), followed by four shorts for each key (key id, location,
+count and value). Only keys with a single short value stored in the
+directory itself (location 0) are kept.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GeoKeys {
+    keys: HashMap<u16, u16>,
+}
+
+impl GeoKeys {
+    pub fn parse(directory: &[u16]) -> Self {
+        let mut keys = HashMap::new();
+
+        if let Some(n) = directory.get(3) {
+            for entry in directory[4..].chunks_exact(4).take(*n as usize) {
+                if let [id, 0, 1, value] = entry {
+                    keys.insert(*id, *value);
+                }
+            }
+        }
+
+        Self { keys }
+    }
+
+    pub fn get(&self, key: u16) -> Option<u16> {
+        self.keys.get(&key).copied()
+    }
+
+    // EPSG code of the projected CRS (ProjectedCSTypeGeoKey), if given as a
+    // code
+    pub fn projected_crs(&self) -> Option<u32> {
+        self.get(PROJECTED_CS_TYPE_GEO_KEY)
+            .filter(|c| *c != 0 && *c != USER_DEFINED)
+            .map(u32::from)
+    }
+
+    // Raster type (GTRasterTypeGeoKey). Pixel is area is the default.
+    pub fn raster_type(&self) -> RasterType {
+        match self.get(GT_RASTER_TYPE_GEO_KEY) {
+            Some(2) => RasterType::PixelIsPoint,
+            _ => RasterType::PixelIsArea,
+        }
+    }
+}
+
+/*
+How raster positions relate to model coordinates. With pixel is area, a
+tie point gives the corner of a pixel. With pixel is point, it gives the
+centre of the pixel.
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RasterType {
+    #[default]
+    PixelIsArea,
+    PixelIsPoint,
+}
+
+impl RasterType {
+    pub fn code(&self) -> u8 {
+        match self {
+            RasterType::PixelIsArea => 1,
+            RasterType::PixelIsPoint => 2,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            2 => RasterType::PixelIsPoint,
+            _ => RasterType::PixelIsArea,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::geokeys::{GeoKeys, RasterType};
+
+    #[test]
+    fn raster_type() {
+        // Model type projected, raster type point, projected CRS 25833
+        let keys = GeoKeys::parse(&[1, 1, 0, 3,
+                                    1024, 0, 1, 1,
+                                    1025, 0, 1, 2,
+                                    3072, 0, 1, 25833]);
+        assert_eq!(keys.raster_type(), RasterType::PixelIsPoint);
+        assert_eq!(keys.projected_crs(), Some(25833));
+
+        let keys = GeoKeys::parse(&[1, 1, 0, 1, 1025, 0, 1, 1]);
+        assert_eq!(keys.raster_type(), RasterType::PixelIsArea);
+        assert_eq!(keys.projected_crs(), None);
+        assert_eq!(GeoKeys::parse(&[]).raster_type(), RasterType::PixelIsArea);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::coord::Coord;
+
+/*
+Geometry helpers for matching map footprints against regions. Rectangles are
+given by their north-west and south-east corners.
+ */
+
+// Check if point p is within the polygon, using the even-odd rule
+pub fn point_in_polygon(p: &Coord, polygon: &[Coord]) -> bool {
+    let mut inside = false;
+    let mut j = polygon.len().wrapping_sub(1);
+
+    for i in 0..polygon.len() {
+        let (a, b) = (polygon[i], polygon[j]);
+        if (a.n > p.n) != (b.n > p.n) &&
+            p.e < (b.e - a.e)*(p.n - a.n)/(b.n - a.n) + a.e {
+            inside = !inside;
+        }
+        j = i;
+    }
+
+    inside
+}
+
+fn cross(o: &Coord, a: &Coord, b: &Coord) -> f64 {
+    (a.e - o.e)*(b.n - o.n) - (a.n - o.n)*(b.e - o.e)
+}
+
+// Check if the segments a-b and c-d intersect
+pub fn segments_intersect(a: &Coord, b: &Coord, c: &Coord, d: &Coord) -> bool {
+    let d1 = cross(c, d, a);
+    let d2 = cross(c, d, b);
+    let d3 = cross(a, b, c);
+    let d4 = cross(a, b, d);
+
+    if ((d1 > 0.0 && d2 < 0.0) || (d1 < 0.0 && d2 > 0.0)) &&
+        ((d3 > 0.0 && d4 < 0.0) || (d3 < 0.0 && d4 > 0.0)) {
+        return true;
+    }
+
+    // Collinear or touching segments
+    let on_segment = |p: &Coord, q: &Coord, r: &Coord| {
+        r.e >= p.e.min(q.e) && r.e <= p.e.max(q.e) &&
+            r.n >= p.n.min(q.n) && r.n <= p.n.max(q.n)
+    };
+
+    (d1 == 0.0 && on_segment(c, d, a)) || (d2 == 0.0 && on_segment(c, d, b)) ||
+        (d3 == 0.0 && on_segment(a, b, c)) || (d4 == 0.0 && on_segment(a, b, d))
+}
+
+// Distance from point p to the segment a-b
+pub fn point_segment_distance(p: &Coord, a: &Coord, b: &Coord) -> f64 {
+    let ab = *b - *a;
+    let len_sq = ab.abs_sq();
+    if len_sq == 0.0 {
+        return (*p - *a).abs();
+    }
+
+    let t = ((*p - *a).dot(&ab)/len_sq).clamp(0.0, 1.0);
+    (*p - (*a + ab*t)).abs()
+}
+
+fn rect_corners(nw: &Coord, se: &Coord) -> [Coord; 4] {
+    [*nw, Coord::new(se.e, nw.n), *se, Coord::new(nw.e, se.n)]
+}
+
+fn in_rect(p: &Coord, nw: &Coord, se: &Coord) -> bool {
+    p.e >= nw.e && p.e <= se.e && p.n <= nw.n && p.n >= se.n
+}
+
+// Distance from the segment a-b to the rectangle. Zero if they intersect.
+pub fn segment_rect_distance(a: &Coord, b: &Coord, nw: &Coord, se: &Coord)
+                             -> f64 {
+    if in_rect(a, nw, se) || in_rect(b, nw, se) {
+        return 0.0;
+    }
+
+    let corners = rect_corners(nw, se);
+    let mut d = f64::INFINITY;
+
+    for i in 0..4 {
+        let (c, e) = (&corners[i], &corners[(i + 1) % 4]);
+        if segments_intersect(a, b, c, e) {
+            return 0.0;
+        }
+        d = d.min(point_segment_distance(c, a, b))
+            .min(point_segment_distance(a, c, e))
+            .min(point_segment_distance(b, c, e));
+    }
+
+    d
+}
+
+/*
+Parameter t in [0, 1] of the point where the segment a-b enters the
+rectangle, or None if it misses the rectangle. Zero if a is inside.
+ */
+pub fn segment_rect_entry(a: &Coord, b: &Coord, nw: &Coord, se: &Coord)
+                          -> Option<f64> {
+    let d = *b - *a;
+    let (mut t0, mut t1) = (0.0f64, 1.0f64);
+
+    // Clip against each pair of parallel edges (Liang-Barsky)
+    for (p, lo, hi, dp) in [(a.e, nw.e, se.e, d.e), (a.n, se.n, nw.n, d.n)] {
+        if dp == 0.0 {
+            if p < lo || p > hi {
+                return None;
+            }
+            continue;
+        }
+
+        let (mut u0, mut u1) = ((lo - p)/dp, (hi - p)/dp);
+        if u0 > u1 {
+            std::mem::swap(&mut u0, &mut u1);
+        }
+        t0 = t0.max(u0);
+        t1 = t1.min(u1);
+        if t0 > t1 {
+            return None;
+        }
+    }
+
+    Some(t0)
+}
+
+// Check if the rectangle and the polygon overlap
+pub fn rect_intersects_polygon(nw: &Coord, se: &Coord, polygon: &[Coord])
+                               -> bool {
+    let corners = rect_corners(nw, se);
+
+    if polygon.iter().any(|p| in_rect(p, nw, se)) ||
+        corners.iter().any(|c| point_in_polygon(c, polygon)) {
+        return true;
+    }
+
+    for i in 0..polygon.len() {
+        let (a, b) = (&polygon[i], &polygon[(i + 1) % polygon.len()]);
+        for j in 0..4 {
+            if segments_intersect(a, b, &corners[j], &corners[(j + 1) % 4]) {
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coord::Coord;
+    use crate::geometry::*;
+
+    fn square() -> Vec<Coord> {
+        vec![Coord::new(0.0, 0.0), Coord::new(10.0, 0.0),
+             Coord::new(10.0, 10.0), Coord::new(0.0, 10.0)]
+    }
+
+    #[test]
+    fn polygon() {
+        assert!(point_in_polygon(&Coord::new(5.0, 5.0), &square()));
+        assert!(!point_in_polygon(&Coord::new(15.0, 5.0), &square()));
+
+        // Rectangle inside, containing, crossing and outside the polygon
+        assert!(rect_intersects_polygon(&Coord::new(2.0, 8.0),
+                                        &Coord::new(4.0, 6.0), &square()));
+        assert!(rect_intersects_polygon(&Coord::new(-5.0, 20.0),
+                                        &Coord::new(20.0, -5.0), &square()));
+        assert!(rect_intersects_polygon(&Coord::new(-5.0, 6.0),
+                                        &Coord::new(20.0, 4.0), &square()));
+        assert!(!rect_intersects_polygon(&Coord::new(12.0, 8.0),
+                                         &Coord::new(14.0, 6.0), &square()));
+    }
+
+    #[test]
+    fn segment_distance() {
+        let nw = Coord::new(0.0, 10.0);
+        let se = Coord::new(10.0, 0.0);
+
+        assert_eq!(segment_rect_distance(&Coord::new(-5.0, 5.0),
+                                         &Coord::new(15.0, 5.0), &nw, &se),
+                   0.0);
+        assert_eq!(segment_rect_distance(&Coord::new(-5.0, 15.0),
+                                         &Coord::new(15.0, 15.0), &nw, &se),
+                   5.0);
+        assert_eq!(point_segment_distance(&Coord::new(3.0, 4.0),
+                                          &Coord::new(0.0, 0.0),
+                                          &Coord::new(0.0, 0.0)), 5.0);
+    }
+
+    #[test]
+    fn segment_entry() {
+        let nw = Coord::new(0.0, 10.0);
+        let se = Coord::new(10.0, 0.0);
+
+        assert_eq!(segment_rect_entry(&Coord::new(-10.0, 5.0),
+                                      &Coord::new(10.0, 5.0), &nw, &se),
+                   Some(0.5));
+        assert_eq!(segment_rect_entry(&Coord::new(5.0, 5.0),
+                                      &Coord::new(20.0, 5.0), &nw, &se),
+                   Some(0.0));
+        assert_eq!(segment_rect_entry(&Coord::new(-10.0, 15.0),
+                                      &Coord::new(20.0, 15.0), &nw, &se),
+                   None);
+        assert_eq!(segment_rect_entry(&Coord::new(-10.0, 5.0),
+                                      &Coord::new(-5.0, 5.0), &nw, &se),
+                   None);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::coord::Coord;
+use crate::geokeys::RasterType;
+
+use serde::{Deserialize, Serialize};
+
+// ModelTransformation tag of GeoTIFF files
+pub const MODEL_TRANSFORMATION_TAG: u16 = 0x85D8;
+
+/*
+Affine transform from raster positions to model coordinates, with the
+coefficients in the order used by GDAL:
+
+  e = c[0] + x*c[1] + y*c[2]
+  n = c[3] + x*c[4] + y*c[5]
+
+Raster position (x, y) is measured from the outer corner of the first pixel,
+so that pixel (x, y) covers [x, x + 1) x [y, y + 1).
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GeoTransform(pub [f64; 6]);
+
+impl GeoTransform {
+    // North-up transform with north-west corner nw and pixel size delta
+    pub fn north_up(nw: Coord, delta: Coord) -> Self {
+        Self([nw.e, delta.e, 0.0, nw.n, 0.0, -delta.n])
+    }
+
+    // Transform from a tie point (ModelTiepointTag) mapping raster position
+    // (i, j) to model coordinate tie, and the pixel scale
+    // (ModelPixelScaleTag)
+    pub fn from_tie_point(i: f64, j: f64, tie: (f64, f64),
+                          scale: (f64, f64)) -> Self {
+        Self([tie.0 - i*scale.0, scale.0, 0.0,
+              tie.1 + j*scale.1, 0.0, -scale.1])
+    }
+
+    // Transform from the 4x4 matrix of the ModelTransformationTag, given
+    // row by row
+    pub fn from_matrix(m: &[f64]) -> Option<Self> {
+        if m.len() < 8 {
+            return None;
+        }
+
+        Some(Self([m[3], m[0], m[1], m[7], m[4], m[5]]))
+    }
+
+    /*
+    Transform with raster positions measured from the corner of the first
+    pixel, from a transform of a GeoTIFF file with the given raster type.
+    With pixel is point, raster position (0, 0) is the centre of the first
+    pixel.
+     */
+    pub fn to_area(&self, raster_type: RasterType) -> Self {
+        let c = self.0;
+
+        match raster_type {
+            RasterType::PixelIsArea => *self,
+            RasterType::PixelIsPoint =>
+                Self([c[0] - 0.5*(c[1] + c[2]), c[1], c[2],
+                      c[3] - 0.5*(c[4] + c[5]), c[4], c[5]]),
+        }
+    }
+
+    // Check if the raster is north-up, with square or non-square pixels
+    pub fn is_north_up(&self) -> bool {
+        let c = self.0;
+        c[2] == 0.0 && c[4] == 0.0 && c[1] > 0.0 && c[5] < 0.0
+    }
+
+    fn det(&self) -> f64 {
+        let c = self.0;
+        c[1]*c[5] - c[2]*c[4]
+    }
+
+    pub fn is_invertible(&self) -> bool {
+        let d = self.det();
+        d != 0.0 && d.is_finite()
+    }
+
+    // Linear part of the transform, without the origin
+    pub fn linear(&self) -> [f64; 4] {
+        let c = self.0;
+        [c[1], c[2], c[4], c[5]]
+    }
+
+    // Model coordinate of raster position (x, y)
+    pub fn apply(&self, x: f64, y: f64) -> Coord {
+        let c = self.0;
+        Coord::new(c[0] + x*c[1] + y*c[2], c[3] + x*c[4] + y*c[5])
+    }
+
+    // Raster position of a model coordinate. The transform must be
+    // invertible.
+    pub fn invert(&self, coord: &Coord) -> (f64, f64) {
+        let c = self.0;
+        let (de, dn) = (coord.e - c[0], coord.n - c[3]);
+
+        if c[2] == 0.0 && c[4] == 0.0 {
+            return (de/c[1], dn/c[5]);
+        }
+
+        let d = self.det();
+        ((c[5]*de - c[2]*dn)/d, (c[1]*dn - c[4]*de)/d)
+    }
+
+    // Gradient (dh/de, dh/dn) in model coordinates, from the gradient
+    // (dh/dx, dh/dy) in raster positions
+    pub fn gradient(&self, dx: f64, dy: f64) -> (f64, f64) {
+        let c = self.0;
+
+        if c[2] == 0.0 && c[4] == 0.0 {
+            return (dx/c[1], dy/c[5]);
+        }
+
+        let d = self.det();
+        ((c[5]*dx - c[4]*dy)/d, (c[1]*dy - c[2]*dx)/d)
+    }
+
+    // Size of the pixels along the raster axes
+    pub fn pixel_size(&self) -> Coord {
+        let c = self.0;
+        Coord::new(c[1].hypot(c[4]), c[2].hypot(c[5]))
+    }
+
+    // Corners of the bounding box of the area covered by a raster of width
+    // x height pixels
+    pub fn bounds(&self, width: usize, height: usize) -> (Coord, Coord) {
+        let (w, h) = (width as f64, height as f64);
+        let corners = [self.apply(0.0, 0.0), self.apply(w, 0.0),
+                       self.apply(0.0, h), self.apply(w, h)];
+
+        let e0 = corners.iter().map(|c| c.e).fold(f64::INFINITY, f64::min);
+        let e1 = corners.iter().map(|c| c.e).fold(f64::NEG_INFINITY, f64::max);
+        let n0 = corners.iter().map(|c| c.n).fold(f64::INFINITY, f64::min);
+        let n1 = corners.iter().map(|c| c.n).fold(f64::NEG_INFINITY, f64::max);
+
+        (Coord::new(e0, n1), Coord::new(e1, n0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coord::Coord;
+    use crate::geokeys::RasterType;
+    use crate::geotransform::GeoTransform;
+
+    #[test]
+    fn north_up() {
+        let tie = (100000.0, 6800000.0);
+        let t = GeoTransform::from_tie_point(0.0, 0.0, tie, (10.0, 10.0));
+        assert!(t.is_north_up());
+        assert_eq!(t, GeoTransform::north_up(Coord::new(100000.0, 6800000.0),
+                                             Coord::new(10.0, 10.0)));
+
+        // The tie point is the corner or the centre of the first pixel
+        assert_eq!(t.to_area(RasterType::PixelIsArea).apply(0.0, 0.0),
+                   Coord::new(100000.0, 6800000.0));
+        assert_eq!(t.to_area(RasterType::PixelIsPoint).apply(0.0, 0.0),
+                   Coord::new(99995.0, 6800005.0));
+        let t = GeoTransform::from_tie_point(2.0, 1.0, tie, (10.0, 10.0));
+        assert_eq!(t.apply(0.0, 0.0), Coord::new(99980.0, 6800010.0));
+
+        assert_eq!(t.invert(&Coord::new(100005.0, 6799990.0)), (2.5, 2.0));
+        assert_eq!(t.gradient(1.0, 1.0), (0.1, -0.1));
+        assert_eq!(t.bounds(10, 5), (Coord::new(99980.0, 6800010.0),
+                                     Coord::new(100080.0, 6799960.0)));
+    }
+
+    #[test]
+    fn rotated() {
+        // Raster x runs north and y runs east, with 10 m by 20 m pixels
+        let t = GeoTransform::from_matrix(&[0.0, 20.0, 0.0, 100.0,
+                                            10.0, 0.0, 0.0, 200.0,
+                                            0.0, 0.0, 0.0, 0.0,
+                                            0.0, 0.0, 0.0, 1.0]).unwrap();
+        assert!(!t.is_north_up());
+        assert!(t.is_invertible());
+        assert_eq!(t.apply(1.0, 2.0), Coord::new(140.0, 210.0));
+        assert_eq!(t.invert(&Coord::new(140.0, 210.0)), (1.0, 2.0));
+        assert_eq!(t.pixel_size(), Coord::new(10.0, 20.0));
+        assert_eq!(t.bounds(3, 2), (Coord::new(100.0, 230.0),
+                                    Coord::new(140.0, 200.0)));
+
+        // h = x + y, so h increases by 1 every 10 m north and 20 m east
+        assert_eq!(t.gradient(1.0, 1.0), (0.05, 0.1));
+
+        assert!(!GeoTransform([0.0, 1.0, 2.0, 0.0, 2.0, 4.0]).is_invertible());
+        assert_eq!(GeoTransform::from_matrix(&[1.0, 2.0]), None);
+    }
+}
@@ -0,0 +1,300 @@
+use crate::errors::*;
+use crate::cache::temp_path;
+use crate::coord::Coord;
+use crate::map::Map;
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"HDAI";
+// Version of the map record layout. Bump when the records change, and keep
+// reading the older versions in Map::decode.
+const SCHEMA_VERSION: u32 = 2;
+// Magic, schema version, record count, payload length, payload checksum and
+// header checksum
+const HEADER_LEN: usize = 4 + 4 + 8 + 8 + 4 + 4;
+
+/*
+Binary atlas index files. A file holds a header followed by one record for
+each map. The same layout, with another magic, is used for the catalogue of
+index files. All numbers are little endian, so that index files can be shared
+between machines. The header records the schema version of the map records,
+and CRC-32 checksums of the header and the records. Files with another
+schema version, or with a checksum mismatch, are rejected rather than
+misread.
+ */
+
+// Encoder of the fields of map records
+#[derive(Default)]
+pub struct IndexWriter {
+    buf: Vec<u8>,
+}
+
+impl IndexWriter {
+    pub fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, v: f32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn f64(&mut self, v: f64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn str(&mut self, v: &str) {
+        self.u64(v.len() as u64);
+        self.buf.extend_from_slice(v.as_bytes());
+    }
+
+    pub fn coord(&mut self, c: &Coord) {
+        self.f64(c.e);
+        self.f64(c.n);
+    }
+}
+
+// Decoder of the fields of map records
+pub struct IndexReader<'a> {
+    file: &'a str,
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> IndexReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.pos < n {
+            return Err(invalid(self.file, "Truncated map record"));
+        }
+
+        let v = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(v)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    pub fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub fn f64(&mut self) -> Result<f64> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    pub fn str(&mut self) -> Result<String> {
+        let n = self.u64()? as usize;
+        Ok(String::from_utf8(self.take(n)?.to_vec())?)
+    }
+
+    pub fn coord(&mut self) -> Result<Coord> {
+        let e = self.f64()?;
+        Ok(Coord::new(e, self.f64()?))
+    }
+
+    // Coordinate written in single precision, by schema version 1
+    pub fn coord_f32(&mut self) -> Result<Coord> {
+        let e = self.f32()?;
+        Ok(Coord::new(e as f64, self.f32()? as f64))
+    }
+}
+
+fn invalid(file: &str, reason: &str) -> Box<dyn std::error::Error + Send + Sync> {
+    Error::InvalidIndex(file.to_string(), reason.to_string()).into()
+}
+
+fn header(magic: &[u8; 4], version: u32, count: u64, payload: &[u8])
+          -> Vec<u8> {
+    let mut h = Vec::with_capacity(HEADER_LEN);
+    h.extend_from_slice(magic);
+    h.extend_from_slice(&version.to_le_bytes());
+    h.extend_from_slice(&count.to_le_bytes());
+    h.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    h.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    let crc = crc32fast::hash(&h);
+    h.extend_from_slice(&crc.to_le_bytes());
+
+    h
+}
+
+// Check if the file starts like a binary index file
+pub fn is_index_file(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/*
+Write the records in w to file, with the given magic and schema version.
+The file is written to a temporary file and renamed, so that readers never
+see a partly written file.
+ */
+pub fn write_records(file: &str, magic: &[u8; 4], version: u32, count: u64,
+                     w: &IndexWriter) -> Result<()> {
+    let tmp = temp_path(Path::new(file));
+    let mut f = fs::File::create(&tmp)?;
+    f.write_all(&header(magic, version, count, &w.buf))?;
+    f.write_all(&w.buf)?;
+    drop(f);
+    fs::rename(&tmp, file)?;
+
+    Ok(())
+}
+
+/*
+Check the header of file data, and return the schema version, the record
+count and a reader of the records. Schema versions from 1 up to version are
+accepted. The reader must consume all of the records.
+ */
+pub fn read_records<'a>(file: &'a str, data: &'a [u8], magic: &[u8; 4],
+                        version: u32)
+                        -> Result<(u32, u64, IndexReader<'a>)> {
+    if data.len() < HEADER_LEN || !data.starts_with(magic) {
+        return Err(invalid(file, "Unknown file type"));
+    }
+
+    let h = &data[..HEADER_LEN];
+    let field = |i: usize, n: usize| &h[i..i + n];
+
+    if crc32fast::hash(&h[..HEADER_LEN - 4]).to_le_bytes() !=
+        field(HEADER_LEN - 4, 4) {
+        return Err(invalid(file, "Header checksum mismatch"));
+    }
+
+    let v = u32::from_le_bytes(field(4, 4).try_into()?);
+    if v == 0 || v > version {
+        return Err(invalid(file, &format!(
+            "Schema version {} is not supported, expected at most {}",
+            v, version)));
+    }
+
+    let count = u64::from_le_bytes(field(8, 8).try_into()?);
+    let len = u64::from_le_bytes(field(16, 8).try_into()?) as usize;
+    let payload = &data[HEADER_LEN..];
+
+    if payload.len() != len {
+        return Err(invalid(file, "Wrong payload length"));
+    }
+    if crc32fast::hash(payload).to_le_bytes() != field(24, 4) {
+        return Err(invalid(file, "Payload checksum mismatch"));
+    }
+
+    Ok((v, count, IndexReader { file, data: payload, pos: 0 }))
+}
+
+impl IndexReader<'_> {
+    // Check that all records have been read
+    pub fn finish(&self) -> Result<()> {
+        if self.pos != self.data.len() {
+            return Err(invalid(self.file, "Trailing data after records"));
+        }
+
+        Ok(())
+    }
+}
+
+// Write the maps to an index file
+pub fn write_index<'a, I>(file: &str, maps: I) -> Result<()>
+where I: IntoIterator<Item = &'a Map>
+{
+    let mut w = IndexWriter::default();
+    let mut count = 0;
+
+    for m in maps {
+        m.encode(&mut w);
+        count += 1;
+    }
+
+    write_records(file, MAGIC, SCHEMA_VERSION, count, &w)
+}
+
+// Read the maps of an index file
+pub fn read_index(file: &str, data: &[u8]) -> Result<Vec<Map>> {
+    let (version, count, mut r) = read_records(file, data, MAGIC,
+                                               SCHEMA_VERSION)?;
+
+    let mut maps = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        maps.push(Map::decode(&mut r, version)?);
+    }
+    r.finish()?;
+
+    Ok(maps)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coord::Coord;
+    use crate::indexfile::*;
+    use crate::map::Map;
+    use crate::testdir::TestDir;
+    use std::fs;
+
+    #[test]
+    fn write_and_read() {
+        let dir = TestDir::new("index");
+        let file = &dir.file("a.atlas.bin");
+
+        let maps = [Map::from_samples("a.tif", Coord::new(100.0, 6800100.0),
+                                      Coord::new(10.0, 10.0), 10,
+                                      vec![0.0; 100]),
+                    Map::from_samples("b.tif", Coord::new(-50.0, 10.0),
+                                      Coord::new(1.0, 1.0), 2,
+                                      vec![0.0; 4])];
+        write_index(file, maps.iter()).unwrap();
+
+        let data = fs::read(file).unwrap();
+        assert!(is_index_file(&data));
+        let read = read_index(file, &data).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[1].fname, "b.tif");
+        assert_eq!(read[1].size(), (2, 2));
+        assert_eq!(read[1].nw, maps[1].nw);
+        assert_eq!(read[1].se, maps[1].se);
+        assert_eq!(read[1].survey, maps[1].survey);
+
+        // Corrupt a record
+        let mut bad = data.clone();
+        let last = bad.len() - 1;
+        bad[last] ^= 1;
+        assert!(read_index(file, &bad).is_err());
+
+        // Corrupt the header
+        let mut bad = data.clone();
+        bad[4] += 1;
+        assert!(read_index(file, &bad).is_err());
+
+        assert!(read_index(file, b"[]").is_err());
+    }
+
+    #[test]
+    fn version_1() {
+        let dir = TestDir::new("index");
+        let file = &dir.file("a.atlas.bin");
+
+        // Map record of the first schema version
+        let mut w = IndexWriter::default();
+        w.str("6700_4_10m_z33.tif");
+        w.str("NDH Hamar 2pkt 2015.zip");
+        w.u64(10);
+        w.u64(10);
+        for v in [100.0, 6800100.0, 200.0, 6800000.0, 10.0, 10.0] {
+            w.f32(v);
+        }
+        write_records(file, MAGIC, 1, 1, &w).unwrap();
+
+        let read = read_index(file, &fs::read(file).unwrap()).unwrap();
+        assert_eq!(read[0].nw, Coord::new(100.0, 6800100.0));
+        assert_eq!(read[0].project(), "NDH Hamar 2pkt 2015");
+        assert_eq!(read[0].crs, None);
+    }
+}
@@ -0,0 +1,253 @@
+use serde::{Deserialize, Serialize};
+
+/*
+Interpolation of height samples. Positions are given in pixel units from the
+north-west corner of the raster, with x increasing eastwards and y increasing
+southwards. Pixel (x, y) covers the area [x, x + 1) x [y, y + 1), and its
+sample value is taken to be at the centre of the pixel.
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Interpolation {
+    // Value of the pixel containing the point
+    #[default]
+    Nearest,
+    // Linear interpolation between the four surrounding pixel centres
+    Bilinear,
+    // Catmull-Rom interpolation over the sixteen surrounding pixel centres
+    Bicubic,
+}
+
+impl Interpolation {
+    /*
+    Return the range of pixels needed to interpolate at pixel position
+    (px, py), as the inclusive corners (x0, y0, x1, y1).
+     */
+    pub fn window(&self, px: f64, py: f64) -> (isize, isize, isize, isize) {
+        match self {
+            Interpolation::Nearest => {
+                let x = px.floor() as isize;
+                let y = py.floor() as isize;
+                (x - 1, y - 1, x + 1, y + 1)
+            },
+            Interpolation::Bilinear => {
+                let x = (px - 0.5).floor() as isize;
+                let y = (py - 0.5).floor() as isize;
+                (x, y, x + 1, y + 1)
+            },
+            Interpolation::Bicubic => {
+                let x = (px - 0.5).floor() as isize;
+                let y = (py - 0.5).floor() as isize;
+                (x - 1, y - 1, x + 2, y + 2)
+            },
+        }
+    }
+}
+
+// Catmull-Rom weights for the samples at -1, 0, 1 and 2
+fn cubic_weights(t: f32) -> [f32; 4] {
+    let t2 = t*t;
+    let t3 = t2*t;
+
+    [
+        (-t3 + 2.0*t2 - t)*0.5,
+        (3.0*t3 - 5.0*t2 + 2.0)*0.5,
+        (-3.0*t3 + 4.0*t2 + t)*0.5,
+        (t3 - t2)*0.5,
+    ]
+}
+
+// Derivatives of the Catmull-Rom weights with respect to t
+fn cubic_weights_deriv(t: f32) -> [f32; 4] {
+    let t2 = t*t;
+
+    [
+        (-3.0*t2 + 4.0*t - 1.0)*0.5,
+        (9.0*t2 - 10.0*t)*0.5,
+        (-9.0*t2 + 8.0*t + 1.0)*0.5,
+        (3.0*t2 - 2.0*t)*0.5,
+    ]
+}
+
+// Weighted sample, where samples with zero weight do not count even if NaN
+fn weighted(w: f32, p: f32) -> f32 {
+    if w == 0.0 { 0.0 } else { w*p }
+}
+
+// Mean of the one-sided differences that are not NaN. The difference is NaN
+// if both are.
+fn central(d1: f32, d2: f32) -> f32 {
+    match (d1.is_nan(), d2.is_nan()) {
+        (false, false) => (d1 + d2)*0.5,
+        (false, true) => d1,
+        _ => d2,
+    }
+}
+
+/*
+Interpolate the height at pixel position (px, py). The sample function
+returns the value of pixel (x, y), or None if the pixel is not available.
+NoData samples are given as NaN. The return value is the triple
+(height, dh/dx, dh/dy) in pixel units, or None if some of the needed samples
+are missing. The height is NaN only if a sample with non-zero weight is NaN.
+With nearest, the gradient is one-sided next to NaN samples. Otherwise, the
+gradient is NaN if some sample it depends on is NaN.
+ */
+pub fn interpolate<F>(mode: Interpolation, px: f64, py: f64, sample: F)
+                      -> Option<(f32, f32, f32)>
+where F: Fn(isize, isize) -> Option<f32>
+{
+    match mode {
+        Interpolation::Nearest => {
+            let x = px.floor() as isize;
+            let y = py.floor() as isize;
+
+            // The gradient is deduced from the neighbouring samples
+            let h = sample(x, y)?;
+            let dx_1 = h - sample(x - 1, y)?;
+            let dx_2 = sample(x + 1, y)? - h;
+            let dy_1 = h - sample(x, y - 1)?;
+            let dy_2 = sample(x, y + 1)? - h;
+
+            Some((h, central(dx_1, dx_2), central(dy_1, dy_2)))
+        },
+        Interpolation::Bilinear => {
+            let fx = px - 0.5;
+            let fy = py - 0.5;
+            let x = fx.floor() as isize;
+            let y = fy.floor() as isize;
+            let tx = (fx - fx.floor()) as f32;
+            let ty = (fy - fy.floor()) as f32;
+
+            let p00 = sample(x, y)?;
+            let p10 = sample(x + 1, y)?;
+            let p01 = sample(x, y + 1)?;
+            let p11 = sample(x + 1, y + 1)?;
+
+            let top = weighted(1.0 - tx, p00) + weighted(tx, p10);
+            let bottom = weighted(1.0 - tx, p01) + weighted(tx, p11);
+
+            let h = weighted(1.0 - ty, top) + weighted(ty, bottom);
+            let dx = (p10 - p00)*(1.0 - ty) + (p11 - p01)*ty;
+            let dy = bottom - top;
+
+            Some((h, dx, dy))
+        },
+        Interpolation::Bicubic => {
+            let fx = px - 0.5;
+            let fy = py - 0.5;
+            let x = fx.floor() as isize;
+            let y = fy.floor() as isize;
+            let tx = (fx - fx.floor()) as f32;
+            let ty = (fy - fy.floor()) as f32;
+
+            let wx = cubic_weights(tx);
+            let wy = cubic_weights(ty);
+            let dwx = cubic_weights_deriv(tx);
+            let dwy = cubic_weights_deriv(ty);
+
+            let mut h = 0.0;
+            let mut dx = 0.0;
+            let mut dy = 0.0;
+
+            for j in 0..4 {
+                for i in 0..4 {
+                    let p = sample(x + i as isize - 1, y + j as isize - 1)?;
+                    h += weighted(wx[i]*wy[j], p);
+                    dx += weighted(dwx[i]*wy[j], p);
+                    dy += weighted(wx[i]*dwy[j], p);
+                }
+            }
+
+            Some((h, dx, dy))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpolation::*;
+
+    // Samples of the plane h = 2x - 3y + 10, taken at the pixel centres
+    fn plane(x: isize, y: isize) -> Option<f32> {
+        if x < 0 || y < 0 || x >= 10 || y >= 10 {
+            return None;
+        }
+
+        Some(2.0*(x as f32 + 0.5) - 3.0*(y as f32 + 0.5) + 10.0)
+    }
+
+    fn assert_close(a: (f32, f32, f32), b: (f32, f32, f32)) {
+        assert!((a.0 - b.0).abs() < 1e-4, "{:?} != {:?}", a, b);
+        assert!((a.1 - b.1).abs() < 1e-4, "{:?} != {:?}", a, b);
+        assert!((a.2 - b.2).abs() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn nearest() {
+        let r = interpolate(Interpolation::Nearest, 4.2, 5.9, plane).unwrap();
+        assert_close(r, (9.0 - 16.5 + 10.0, 2.0, -3.0));
+    }
+
+    #[test]
+    fn bilinear_plane() {
+        let r = interpolate(Interpolation::Bilinear, 4.2, 5.9, plane).unwrap();
+        assert_close(r, (2.0*4.2 - 3.0*5.9 + 10.0, 2.0, -3.0));
+    }
+
+    #[test]
+    fn bicubic_plane() {
+        let r = interpolate(Interpolation::Bicubic, 4.2, 5.9, plane).unwrap();
+        assert_close(r, (2.0*4.2 - 3.0*5.9 + 10.0, 2.0, -3.0));
+    }
+
+    #[test]
+    fn bicubic_at_sample() {
+        let r = interpolate(Interpolation::Bicubic, 3.5, 3.5,
+                            |x, y| Some((x*x + y) as f32)).unwrap();
+        assert!((r.0 - 12.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn continuous_across_cells() {
+        let f = |x: isize, y: isize| Some(((x*7 + y*13) % 5) as f32);
+        for mode in [Interpolation::Bilinear, Interpolation::Bicubic] {
+            let a = interpolate(mode, 4.4999, 4.2, f).unwrap();
+            let b = interpolate(mode, 4.5001, 4.2, f).unwrap();
+            assert!((a.0 - b.0).abs() < 1e-2);
+        }
+    }
+
+    #[test]
+    fn window() {
+        assert_eq!(Interpolation::Nearest.window(4.2, 5.9), (3, 4, 5, 6));
+        assert_eq!(Interpolation::Bilinear.window(4.2, 5.9), (3, 5, 4, 6));
+        assert_eq!(Interpolation::Bicubic.window(4.2, 5.9), (2, 4, 5, 7));
+    }
+
+    #[test]
+    fn nan_samples() {
+        // NaN at (5, 5), next to the sampled pixel (4, 5)
+        let f = |x: isize, y: isize| {
+            if (x, y) == (5, 5) { Some(f32::NAN) } else { plane(x, y) }
+        };
+
+        let r = interpolate(Interpolation::Nearest, 4.2, 5.9, f).unwrap();
+        assert_close(r, (9.0 - 16.5 + 10.0, 2.0, -3.0));
+        assert!(interpolate(Interpolation::Nearest, 5.2, 5.9, f).unwrap()
+                .0.is_nan());
+
+        // At the centre of pixel (4, 5) only that sample has weight
+        for mode in [Interpolation::Bilinear, Interpolation::Bicubic] {
+            let r = interpolate(mode, 4.5, 5.5, f).unwrap();
+            assert!((r.0 - (9.0 - 16.5 + 10.0)).abs() < 1e-4);
+            assert!(interpolate(mode, 4.7, 5.5, f).unwrap().0.is_nan());
+        }
+    }
+
+    #[test]
+    fn missing_samples() {
+        assert!(interpolate(Interpolation::Nearest, 0.5, 5.0, plane).is_none());
+        assert!(interpolate(Interpolation::Bilinear, 9.7, 5.0, plane).is_none());
+        assert!(interpolate(Interpolation::Bicubic, 1.2, 5.0, plane).is_none());
+    }
+}
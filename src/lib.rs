@@ -3,8 +3,33 @@ mod coord;
 mod mapfolder;
 mod map;
 mod errors;
+mod interpolation;
+mod raster;
+mod cache;
+mod prefetch;
+mod geometry;
+mod spatial;
+mod indexfile;
+mod catalogue;
+mod policy;
+mod survey;
+mod geokeys;
+mod geotransform;
+mod vsifile;
+#[cfg(test)]
+mod testdir;
+#[cfg(test)]
+mod testmaps;
 
-pub use crate::atlas::{MsgSender, MsgReceiver, Atlas};
+pub use crate::atlas::{MsgSender, MsgReceiver, Atlas, MemoryUsage, PreloadSummary,
+                       LookupInfo};
 pub use crate::coord::{Coord, Coord3};
-pub use crate::mapfolder::{set_map_dir, unmount_all_maps};
+pub use crate::mapfolder::{MapFolder, set_map_dir, unmount_all_maps};
 pub use crate::errors::{Error, Result};
+pub use crate::interpolation::Interpolation;
+pub use crate::policy::OverlapPolicy;
+pub use crate::survey::{SurveyInfo, Product};
+pub use crate::geokeys::RasterType;
+pub use crate::geotransform::GeoTransform;
+pub use crate::raster::{BlockSize, SampleStorage};
+pub use crate::prefetch::{Prefetcher, PrefetchHint};
@@ -1,16 +1,28 @@
 use crate::errors::*;
 use crate::coord::Coord;
-use crate::mapfolder::{ZipMount, map_dir};
+use crate::mapfolder::MapFolder;
 use crate::atlas::MsgSender;
+use crate::interpolation::{Interpolation, interpolate};
+use crate::raster::{BlockSize, Raster, SampleStorage, Samples};
+use crate::cache::RasterCache;
+use crate::indexfile::{IndexReader, IndexWriter};
+use crate::survey::{Product, SurveyInfo};
+use crate::geokeys::{GeoKeys, RasterType, GEO_KEY_DIRECTORY_TAG};
+use crate::geotransform::{GeoTransform, MODEL_TRANSFORMATION_TAG};
+use crate::vsifile::VsiFile;
 
 extern crate exif;
 use exif::{Exif, Tag, In, Context, Value};
 use gdal::{Dataset};
-use std::collections::HashSet;
-use std::cell::RefCell;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use serde::{Deserialize, Serialize};
 
+// Height and gradient of a point, (h, dh/dx, dh/dy)
+pub type HeightGradient = (f32, f32, f32);
+
 #[derive(Serialize, Deserialize)]
 pub struct Map {
     pub fname: String,
@@ -20,8 +32,35 @@ pub struct Map {
     pub nw: Coord,
     pub se: Coord,
     pub delta: Coord,
+    // Missing in index files from older versions
+    #[serde(default)]
+    pub survey: SurveyInfo,
+    // NoData value of the band. NaN samples are also taken as NoData.
+    #[serde(default)]
+    pub nodata: Option<f32>,
+    // Registration of the tie point in the GeoTIFF file. The corners above
+    // are always those of the area covered by the pixels.
+    #[serde(default)]
+    pub raster_type: RasterType,
+    // EPSG code of the projected CRS of the map, if known
+    #[serde(default)]
+    pub crs: Option<u32>,
+    // Geotransform of maps that are not north-up, e.g. rotated maps. The
+    // corners above are then those of the bounding box of the map, and delta
+    // is the pixel size. North-up maps are given by nw and delta alone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transform: Option<GeoTransform>,
+    #[serde(skip_serializing, skip_deserializing)]
+    im: RwLock<Raster>,
+    // The GeoTIFF file, kept open while the image is partly loaded, so that
+    // loading more blocks does not open it again. Held while reading the
+    // image, so that concurrent lookups needing the same map only load it
+    // once.
+    #[serde(skip_serializing, skip_deserializing)]
+    dataset: Mutex<Option<Dataset>>,
+    // Atlas clock value of the last lookup, used for LRU eviction
     #[serde(skip_serializing, skip_deserializing)]
-    im: RefCell<Vec<f32>>,
+    last_used: AtomicU64,
 }
 
 impl Map {
@@ -33,47 +72,97 @@ impl Map {
 	None
     }
 
-    fn exif_float(exif: &Exif, tag: Tag, i: usize) -> Option<f32> {
-	if let Some(field) = exif.get_field(tag, In::PRIMARY) {
-	    return match field.value {
-		Value::Double(ref v) if v.len() > i => Some(v[i] as f32),
-		_ => None,
-	    }
+    fn exif_string(exif: &Exif, tag: Tag) -> Option<String> {
+	match exif.get_field(tag, In::PRIMARY)?.value {
+	    Value::Ascii(ref v) if !v.is_empty() =>
+		Some(String::from_utf8_lossy(&v[0]).into_owned()),
+	    _ => None,
+	}
+    }
+
+    fn exif_shorts(exif: &Exif, tag: Tag) -> Option<Vec<u16>> {
+	match exif.get_field(tag, In::PRIMARY)?.value {
+	    Value::Short(ref v) => Some(v.clone()),
+	    _ => None,
 	}
+    }
 
-	None
+    fn exif_doubles(exif: &Exif, tag: Tag) -> Option<Vec<f64>> {
+	match exif.get_field(tag, In::PRIMARY)?.value {
+	    Value::Double(ref v) => Some(v.clone()),
+	    _ => None,
+	}
     }
 
-    pub fn new(fname: &str, zipfile: &str, tx: Option<&MsgSender>)
-               -> Result<Self> {
-	let absfile = format!("{}{}", map_dir(), fname);
-	let file = std::fs::File::open(absfile).unwrap();
-	let mut bufreader = std::io::BufReader::new(&file);
+    /*
+    Geotransform of a GeoTIFF file, from the ModelTransformation tag, or from
+    the ModelTiepoint and ModelPixelScale tags. GDAL is asked for files with
+    neither.
+     */
+    fn read_geotransform(exif: &Exif, raster_type: RasterType, ds: &Dataset)
+                         -> Option<GeoTransform> {
+	let tag = |t| Self::exif_doubles(exif, Tag(Context::Tiff, t));
+
+	let t = match (tag(MODEL_TRANSFORMATION_TAG), tag(0x8482), tag(0x830E)) {
+	    (Some(m), _, _) => GeoTransform::from_matrix(&m),
+	    (None, Some(tie), Some(scale))
+		if tie.len() >= 5 && scale.len() >= 2 =>
+		Some(GeoTransform::from_tie_point(tie[0], tie[1],
+						  (tie[3], tie[4]),
+						  (scale[0], scale[1]))),
+	    _ => None,
+	};
+
+	match t {
+	    Some(t) => Some(t.to_area(raster_type)),
+	    // GDAL always gives the transform from the corner of the first pixel
+	    None => ds.geo_transform().ok().map(GeoTransform),
+	}
+    }
+
+    // Read the map file fname of the map folder, from within zipfile unless
+    // it is empty
+    pub fn new(folder: &MapFolder, fname: &str, zipfile: &str,
+               tx: Option<&MsgSender>) -> Result<Self> {
+	let absfile = folder.map_path(fname, zipfile)?;
+	let file = VsiFile::open(&absfile)?;
+	let mut bufreader = std::io::BufReader::new(file);
 	let exifreader = exif::Reader::new();
 	let exif = exifreader.read_from_container(&mut bufreader).unwrap();
 
 	let width = Self::exif_u32(&exif, Tag::ImageWidth).unwrap() as usize;
 	let height = Self::exif_u32(&exif, Tag::ImageLength).unwrap() as usize;
 
-	let delta = Coord::new(
-	    Self::exif_float(&exif, Tag(Context::Tiff, 0x830E), 0).unwrap(),
-	    Self::exif_float(&exif, Tag(Context::Tiff, 0x830E), 1).unwrap()
-	);
-
-	let nw = Coord::new(
-	    Self::exif_float(&exif, Tag(Context::Tiff, 0x8482),
-			     3).unwrap(),
-	    Self::exif_float(&exif, Tag(Context::Tiff, 0x8482),
-			     4).unwrap()
-	);
-	
-	let se = nw + Coord::new(
-            (width as f32)*delta.e,
-            - (height as f32)*delta.n
-        );
+	let geokeys = Self::exif_shorts(
+	    &exif, Tag(Context::Tiff, GEO_KEY_DIRECTORY_TAG))
+	    .map(|d| GeoKeys::parse(&d)).unwrap_or_default();
+	let raster_type = geokeys.raster_type();
+
+	let ds = Dataset::open(&absfile)?;
+
+	let t = Self::read_geotransform(&exif, raster_type, &ds)
+	    .filter(|t| t.is_invertible())
+	    .ok_or_else(|| Error::InvalidGeoTransform(fname.to_string()))?;
+	let (nw, se) = t.bounds(width, height);
+	let delta = t.pixel_size();
+	let transform = if t.is_north_up() { None } else { Some(t) };
+
+	let nodata = ds.rasterband(1)?.no_data_value().map(|v| v as f32);
+
+	// The CRS is taken from GDAL when the GeoKeys do not give it as an
+	// EPSG code
+	let crs = geokeys.projected_crs().or_else(|| {
+	    ds.spatial_ref().ok()?.auth_code().ok().map(|c| c as u32)
+	});
+
+	let survey = SurveyInfo::new(
+	    fname, zipfile,
+	    Self::exif_string(&exif, Tag::ImageDescription).as_deref(),
+	    Self::exif_string(&exif, Tag::DateTime).as_deref());
 
         if let Some(some_tx) = tx {
-            some_tx.send(format!("Map: {} {} -> {}", fname, nw, se)).unwrap();
+            some_tx.send(format!("Map: {} {} -> {} (EPSG:{})", fname, nw, se,
+                                 crs.unwrap_or(0))).unwrap();
         }
 
 	Ok(Self {
@@ -84,88 +173,286 @@ impl Map {
 	    nw: nw,
 	    se: se,
 	    delta: delta,
+	    survey,
+	    nodata,
+	    raster_type,
+	    crs,
+	    transform,
 	    im: Default::default(),
+	    dataset: Default::default(),
+	    last_used: Default::default(),
 	})
     }
 
+    // Write the map record of index files
+    pub(crate) fn encode(&self, w: &mut IndexWriter) {
+        w.str(&self.fname);
+        w.str(&self.zipfile);
+        w.u64(self.width as u64);
+        w.u64(self.height as u64);
+        w.coord(&self.nw);
+        w.coord(&self.se);
+        w.coord(&self.delta);
+        w.str(&self.survey.project);
+        w.u64(self.survey.year.unwrap_or(0) as u64);
+        w.u8(self.survey.product.code());
+        w.f32(self.nodata.unwrap_or(f32::NAN));
+        w.u8(self.raster_type.code());
+        w.u64(self.crs.unwrap_or(0) as u64);
+        match &self.transform {
+            Some(t) => {
+                w.u8(1);
+                for c in t.0 {
+                    w.f64(c);
+                }
+            },
+            None => w.u8(0),
+        }
+    }
+
+    /*
+    Read a map record written by encode, with the given schema version.
+    Records from version 1 only hold the file names, size and extent, with
+    single precision coordinates. The survey is then taken from the file
+    names, only NaN samples are taken as NoData, pixels are taken to be areas
+    and the map to be north-up, until the index is rebuilt.
+     */
+    pub(crate) fn decode(r: &mut IndexReader, version: u32) -> Result<Self> {
+        let coord = |r: &mut IndexReader| {
+            if version >= 2 { r.coord() } else { r.coord_f32() }
+        };
+
+        let mut m = Self {
+            fname: r.str()?,
+            zipfile: r.str()?,
+            width: r.u64()? as usize,
+            height: r.u64()? as usize,
+            nw: coord(r)?,
+            se: coord(r)?,
+            delta: coord(r)?,
+            survey: SurveyInfo::default(),
+            nodata: None,
+            raster_type: RasterType::PixelIsArea,
+            crs: None,
+            transform: None,
+            im: Default::default(),
+            dataset: Default::default(),
+            last_used: Default::default(),
+        };
+
+        if version < 2 {
+            m.survey = SurveyInfo::new(&m.fname, &m.zipfile, None, None);
+            return Ok(m);
+        }
+
+        m.survey = SurveyInfo {
+            project: r.str()?,
+            year: Some(r.u64()? as u32).filter(|y| *y != 0),
+            product: Product::from_code(r.u8()?),
+        };
+        m.nodata = Some(r.f32()?).filter(|v| !v.is_nan());
+        m.raster_type = RasterType::from_code(r.u8()?);
+        m.crs = Some(r.u64()? as u32).filter(|c| *c != 0);
+
+        if r.u8()? != 0 {
+            let mut c = [0.0; 6];
+            for v in c.iter_mut() {
+                *v = r.f64()?;
+            }
+            m.transform = Some(GeoTransform(c));
+        }
+
+        Ok(m)
+    }
+
+    // Width and height of the map in pixels
+    pub fn size(&self) -> (usize, usize) {
+	(self.width, self.height)
+    }
+
+    // Nominal resolution, used to group maps into atlases
     pub fn resolution(&self) -> f32 {
-	self.delta.n
+	self.delta.n as f32
     }
     
-    pub fn coord_to_hash(coord: &Coord) -> i32 {
-        let h = (((coord.n - 6400000.0)/500.0) as i32)*10000 +
-	    (((coord.e + 120000.0)/500.0) as i32);
-        h
-    }
-
-    pub fn hashes(&self) -> HashSet<i32> {
-        let mut ret = HashSet::new();
-
-        let mut y = self.se.n;
-        while y <= self.nw.n {
-            let mut x = self.nw.e;
-	    while x <= self.se.e {
-                ret.insert(Map::coord_to_hash(&Coord::new(x, y)));
-                x += 500.0;
-	    }
-	    y += 500.0;
-	}
+    // Name of the project the map belongs to, or empty if unknown
+    pub fn project(&self) -> &str {
+	&self.survey.project
+    }
+
+    pub fn survey_year(&self) -> Option<u32> {
+	self.survey.year
+    }
 
-        ret
+    // Check if the map overlaps the area between the corners nw and se
+    pub fn intersects(&self, nw: &Coord, se: &Coord) -> bool {
+	self.nw.e <= se.e && self.se.e >= nw.e &&
+	    self.se.n <= nw.n && self.nw.n >= se.n
     }
 
+    // Check if the whole image is loaded
     pub fn is_loaded(&self) -> bool {
-        self.im != Default::default()
+        self.im.read().unwrap().is_complete()
     }
-    
-    pub fn load_image(&self, tx: Option<&MsgSender>) -> Result<()> {
+
+    // Check if any part of the image is loaded
+    pub fn is_resident(&self) -> bool {
+        !self.im.read().unwrap().is_empty()
+    }
+
+    // Number of bytes held by the loaded image
+    pub fn image_bytes(&self) -> usize {
+        self.im.read().unwrap().bytes()
+    }
+
+    // Largest difference between a loaded sample and the value in the file,
+    // which is non-zero for quantized images
+    pub fn max_error(&self) -> f32 {
+        self.im.read().unwrap().max_error()
+    }
+
+    pub fn touch(&self, time: u64) {
+        self.last_used.store(time, Ordering::Relaxed);
+    }
+
+    pub fn last_used(&self) -> u64 {
+        self.last_used.load(Ordering::Relaxed)
+    }
+
+    // Free the height data. The map is loaded again on next use.
+    pub fn unload_image(&self) {
+        let mut dataset = self.dataset.lock().unwrap();
+        self.im.write().unwrap().clear();
+        *dataset = None;
+    }
+
+    /*
+    Load the height data of the map. If the map is already loaded, or another
+    thread is loading it, the function returns when the image is available
+    without reading it again.
+     */
+    pub fn load_image(&self, folder: &Arc<MapFolder>, tx: Option<&MsgSender>)
+                      -> Result<()> {
+        self.load_window((0, 0, self.width as isize - 1,
+                          self.height as isize - 1),
+                         BlockSize::Whole, SampleStorage::Float, folder, None,
+                         tx)
+    }
+
+    // File the map is read from, the zip file for zipped maps
+    fn source_file(&self, folder: &MapFolder) -> Result<PathBuf> {
+        if !self.zipfile.is_empty() {
+            Ok(PathBuf::from(folder.path(&self.zipfile)?))
+        }
+        else {
+            Ok(PathBuf::from(folder.path(&self.fname)?))
+        }
+    }
+
+    /*
+    Load the blocks of the image overlapping the inclusive pixel window
+    (x0, y0, x1, y1) from the map folder. The block size is only used when no
+    part of the image is loaded. Otherwise the blocks of the loaded image are
+    kept. The blocks read are kept with the given storage. The file stays
+    open until the whole image is loaded or unloaded.
+
+    With a cache, the whole image is mapped from the cache if it is there,
+    and otherwise read as a whole and written to the cache. Images mapped
+    from the cache are kept mapped with float storage, and are quantized
+    from the mapping with quantized storage.
+     */
+    pub fn load_window(&self, window: (isize, isize, isize, isize),
+                       block_size: BlockSize, storage: SampleStorage,
+                       folder: &Arc<MapFolder>, cache: Option<&RasterCache>,
+                       tx: Option<&MsgSender>) -> Result<()> {
+        let (x0, y0, x1, y1) = window;
+
+        let mut dataset = self.dataset.lock().unwrap();
+        if self.im.read().unwrap().is_window_resident(x0, y0, x1, y1) {
+            return Ok(());
+        }
+
+        let empty = self.im.read().unwrap().is_empty();
+
+        if let Some(c) = cache && empty {
+            let source = self.source_file(folder)?;
+            if let Some(samples) = c.read(&self.fname, &source, self.width,
+                                          self.height)? {
+                if let Some(some_tx) = tx {
+                    let _ = some_tx.send(format!("Mapped cached file {}",
+                                                 self.fname));
+                }
+
+                *self.im.write().unwrap() = Raster::whole(
+                    self.width, self.height,
+                    samples.with_storage(storage, self.nodata));
+                return Ok(());
+            }
+        }
+
+        if dataset.is_none() {
+            *dataset = Some(Dataset::open(
+                folder.map_path(&self.fname, &self.zipfile)?)?);
+        }
+        let band = dataset.as_ref().unwrap().rasterband(1)?;
+
+        if empty {
+            let (bw, bh) = match (block_size, cache) {
+                // The cache needs the whole image
+                (_, Some(_)) | (BlockSize::Whole, _) =>
+                    (self.width, self.height),
+                (BlockSize::Native, _) => band.block_size(),
+                (BlockSize::Fixed(w, h), _) => (w, h),
+            };
+            *self.im.write().unwrap() =
+                Raster::new(self.width, self.height, bw, bh);
+        }
+
+        let missing: Vec<usize> = {
+            let raster = self.im.read().unwrap();
+            raster.blocks_in_window(x0, y0, x1, y1).into_iter()
+                .filter(|i| !raster.is_resident(*i))
+                .collect()
+        };
+
         if let Some(some_tx) = tx {
-            some_tx.send(format!("Reading file {}", self.fname)).unwrap();
+            let _ = some_tx.send(format!("Reading {} blocks of file {}",
+                                         missing.len(), self.fname));
         }
 
-	if self.zipfile != "" {
-	    let _ = ZipMount::new(&self.zipfile);
-	}
+        for i in missing {
+            // Copy the block into an f32 vector
+            let (x, y, w, h) = self.im.read().unwrap().block_window(i);
+            let rv = band.read_as::<f32>((x as isize, y as isize), (w, h),
+                                         (w, h), None)?;
+
+            // With a cache, the image is read as a single block
+            if let Some(c) = cache && empty {
+                // Failing to cache the image does not fail the load
+                let r = self.source_file(folder).and_then(|source| {
+                    c.write(&self.fname, &source, self.width, self.height,
+                            &rv.data)
+                });
+                if let (Err(e), Some(some_tx)) = (r, tx) {
+                    let _ = some_tx.send(format!("Unable to cache file {}: {}",
+                                                 self.fname, e));
+                }
+            }
+
+            self.im.write().unwrap().set_samples(
+                i, Samples::store(rv.data, storage, self.nodata));
+        }
+
+        // No more blocks are read from a whole image
+        if self.im.read().unwrap().is_complete() {
+            *dataset = None;
+        }
 
-        let absname = format!("{}{}", map_dir(), self.fname);
-	let im = Dataset::open(absname)?;
-	let band = &im.rasterband(1);
-
-	// Copy the whole raster array into an f32 vector
-	let window_size = (self.width, self.height);
-	let size = (self.width, self.height);
-	let resample_alg = None;
-	let window = (0, 0);
-	let rv = band.as_ref().unwrap().read_as::<f32>(window, window_size,
-                                                       size, resample_alg)?;
-        self.im.replace(rv.data);
 	Ok(())
     }
 
     pub fn lookup(&self, coord: &Coord) -> Result<f32> {
-        /*
-        Lookup height for coordinate. Function will load complete height
-	data if not already loaded.
-	 */
-        let x = ((coord.e - self.nw.e)/self.delta.e) as isize;
-        let y = ((self.nw.n - coord.n)/self.delta.n) as isize;
-
-	// We require the point to be one sample from the map edge. We want the
-	// lookup function to have the same restrictions as the
-	// lookup_with_gradient function.
-        if x < 1 || x >= (self.width as isize) -1 ||
-	    y < 1 || y >= (self.height as isize) -1 {
-		return Err(Error::LookupError(
-                    coord.clone(),
-                    String::from(&self.fname)).into()
-                );
-	    }
-
-        if !self.is_loaded() {
-	    return Err(Error::MapNotLoaded(String::from(&self.fname)).into());
-	}
-
-	Ok(self.im.borrow()[x as usize + (y as usize)*self.width])
+        self.lookup_interpolated(coord, Interpolation::Nearest)
     }
 
     /*
@@ -174,36 +461,174 @@ impl Map {
      */
     pub fn lookup_with_gradient(&self, coord: &Coord)
                                 -> Result<(f32, f32, f32)> {
-        /*
-        Lookup height for coordinate. Function will load complete height
-	data if not already loaded.
-	 */
-        let x = ((coord.e - self.nw.e)/self.delta.e) as isize;
-        let y = ((self.nw.n - coord.n)/self.delta.n) as isize;
-
-	// We require the point to be one sample from the map edge in order for
-	// us to calculate the gradient.
-        if x < 1 || x >= (self.width as isize) - 1 ||
-	    y < 1 || y >= (self.height as isize) - 1 {
-		return Err(Error::LookupError(
-                    coord.clone(),
-                    String::from(&self.fname)).into()
-                );
-	    }
-
-        if !self.is_loaded() {
-	    return Err(Error::MapNotLoaded(String::from(&self.fname)).into());
-	}
+        self.lookup_with_gradient_interpolated(coord, Interpolation::Nearest)
+    }
+
+    pub fn lookup_interpolated(&self, coord: &Coord,
+                               interpolation: Interpolation) -> Result<f32> {
+        Ok(self.lookup_with_gradient_interpolated(coord, interpolation)?.0)
+    }
+
+    // Check if all samples needed to interpolate at coord are within the map
+    pub fn covers(&self, coord: &Coord, interpolation: Interpolation) -> bool {
+        let (x0, y0, x1, y1) = self.window(coord, interpolation);
+
+        x0 >= 0 && x1 < self.width as isize &&
+            y0 >= 0 && y1 < self.height as isize
+    }
+
+    // Check if coordinate is within the map
+    pub fn contains(&self, coord: &Coord) -> bool {
+        let (px, py) = self.pixel(coord);
+
+        px >= 0.0 && px < self.width as f64 &&
+            py >= 0.0 && py < self.height as f64
+    }
+
+    // Pixel window needed to interpolate at coord
+    pub fn window(&self, coord: &Coord, interpolation: Interpolation)
+                  -> (isize, isize, isize, isize) {
+        let (px, py) = self.pixel(coord);
+        interpolation.window(px, py)
+    }
+
+    // Geotransform of the map, from raster positions to coordinates
+    pub fn geotransform(&self) -> GeoTransform {
+        self.transform.unwrap_or_else(
+            || GeoTransform::north_up(self.nw, self.delta))
+    }
+
+    // Fractional pixel position of coordinate, from the outer corner of the
+    // first pixel. Pixel (x, y) covers [x, x + 1) x [y, y + 1) whatever the
+    // raster type.
+    pub fn pixel(&self, coord: &Coord) -> (f64, f64) {
+        self.geotransform().invert(coord)
+    }
+
+    // Coordinate of the centre of pixel (x, y). The pixel may be outside of
+    // the map.
+    pub fn pixel_centre(&self, x: isize, y: isize) -> Coord {
+        self.geotransform().apply(x as f64 + 0.5, y as f64 + 0.5)
+    }
+
+    // Gradient (dh/de, dh/dn) from the gradient (dh/dx, dh/dy) in pixels
+    pub fn model_gradient(&self, dx: f32, dy: f32) -> (f32, f32) {
+        let (de, dn) = self.geotransform().gradient(dx as f64, dy as f64);
+        (de as f32, dn as f32)
+    }
 
-	let i = x as usize + (y as usize)*self.width;
-	let a = self.im.borrow();
-        let h = a[i];
-	let dx_1 = h - a[i - 1];
-	let dx_2 = a[i + 1] - h;
-	let dy_1 = a[i - (self.width as usize)] - h;
-	let dy_2 = h - a[i + (self.width as usize)];
+    // Check if the pixels of the maps are aligned the same way and have the
+    // same size
+    pub fn same_grid(&self, other: &Map) -> bool {
+        self.geotransform().linear() == other.geotransform().linear()
+    }
+
+    // Check if sample value v is NoData
+    pub fn is_nodata(&self, v: f32) -> bool {
+        v.is_nan() || Some(v) == self.nodata
+    }
+
+    // Value of pixel (x, y), or None if the pixel is not loaded
+    pub fn pixel_value(&self, x: usize, y: usize) -> Option<f32> {
+        self.im.read().unwrap().value(x, y)
+    }
 
-	Ok((h, (dx_1 + dx_2)*0.5/self.delta.e, (dy_1 + dy_2)*0.5/self.delta.n))
+    /*
+    Lookup height and gradient of coordinate, using the given interpolation.
+    The gradient is the derivative of the interpolated surface, except for
+    nearest interpolation where it is deduced from the neighbouring samples.
+    Returns NoData if any of the samples the height depends on is NoData.
+    The gradient is NaN if it can not be deduced for NoData samples.
+     */
+    pub fn lookup_with_gradient_interpolated(&self, coord: &Coord,
+                                             interpolation: Interpolation)
+                                             -> Result<(f32, f32, f32)> {
+	// We require all samples needed by the interpolation to be within the
+	// map. Plain lookups have the same restrictions as gradient lookups.
+        if !self.covers(coord, interpolation) {
+            return Err(Error::LookupError(
+                coord.clone(),
+                String::from(&self.fname)).into()
+            );
+        }
+
+	let a = self.im.read().unwrap();
+        Ok(self.sample(&a, coord, interpolation)?)
+    }
+
+    /*
+    Lookup height and gradient of several coordinates, locking the image
+    once. Returns MapNotLoaded if the image is not loaded for some of the
+    coordinates, otherwise one result for each coordinate.
+     */
+    pub fn lookup_many(&self, coords: &[Coord], interpolation: Interpolation)
+                       -> Result<Vec<std::result::Result<HeightGradient, Error>>> {
+	let a = self.im.read().unwrap();
+
+        coords.iter().map(|c| {
+            if !self.covers(c, interpolation) {
+                return Ok(Err(Error::LookupError(*c, self.fname.clone())));
+            }
+            match self.sample(&a, c, interpolation) {
+                Err(Error::MapNotLoaded(f)) =>
+                    Err(Error::MapNotLoaded(f).into()),
+                r => Ok(r),
+            }
+        }).collect()
+    }
+
+    /*
+    Interpolate from the loaded image a. The coordinate must be covered by
+    the map. Returns MapNotLoaded if the needed samples are not loaded, and
+    NoData if some of the samples the height depends on are NoData.
+     */
+    fn sample(&self, a: &Raster, coord: &Coord, interpolation: Interpolation)
+              -> std::result::Result<HeightGradient, Error> {
+        let (px, py) = self.pixel(coord);
+        let sample = |x: isize, y: isize| {
+            let v = a.value(x as usize, y as usize)?;
+            Some(if self.is_nodata(v) { f32::NAN } else { v })
+        };
+
+        match interpolate(interpolation, px, py, sample) {
+            Some((h, _, _)) if h.is_nan() =>
+                Err(Error::NoData(*coord, String::from(&self.fname))),
+            Some((h, dx, dy)) => {
+                let (de, dn) = self.model_gradient(dx, dy);
+                Ok((h, de, dn))
+            },
+            None => Err(Error::MapNotLoaded(String::from(&self.fname))),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Map {
+    // Create a loaded map from samples, for testing without GeoTIFF files
+    pub fn from_samples(fname: &str, nw: Coord, delta: Coord, width: usize,
+                        samples: Vec<f32>) -> Self {
+        let height = samples.len()/width;
+        let mut raster = Raster::new(width, height, width, height);
+        raster.set_samples(0, Samples::Owned(samples));
+
+        Self {
+            fname: String::from(fname),
+            zipfile: String::new(),
+            width,
+            height,
+            nw,
+            se: nw + Coord::new((width as f64)*delta.e,
+                                - (height as f64)*delta.n),
+            delta,
+            survey: SurveyInfo::new(fname, "", None, None),
+            nodata: None,
+            raster_type: RasterType::PixelIsArea,
+            crs: None,
+            transform: None,
+            im: RwLock::new(raster),
+            dataset: Default::default(),
+            last_used: Default::default(),
+        }
     }
 }
 
@@ -211,52 +636,272 @@ impl Map {
 mod tests {
     use crate::map::Map;
     use crate::coord::*;
-    use std::collections::HashSet;
+    use crate::interpolation::Interpolation;
+    use crate::raster::{BlockSize, SampleStorage, Samples};
+    use crate::cache::RasterCache;
+    use crate::errors::Error;
+    use crate::geokeys::RasterType;
+    use crate::geotransform::GeoTransform;
+    use crate::mapfolder::MapFolder;
+    use crate::testdir::TestDir;
+    use std::fs;
+    use std::sync::Arc;
+
+    // The test data is found relative to the crate
+    fn folder() -> Arc<MapFolder> {
+	Arc::new(MapFolder::new(""))
+    }
 
     #[test]
     fn new_from_fname() {
-	let _ = Map::new("testdata/6700_4_10m_z33.tif", "", None);
+	let _ = Map::new(&folder(), "testdata/6700_4_10m_z33.tif", "", None);
+    }
+
+    #[test]
+    fn intersects() {
+	let m = Map::from_samples("m", Coord::new(100.0, 6789745.0),
+				  Coord::new(10.0, 10.0), 10, vec![0.0; 100]);
+	assert!(m.intersects(&m.nw, &m.se));
+	assert!(m.intersects(&(m.nw + Coord::new(-10.0, 10.0)),
+			     &(m.nw + Coord::new(10.0, -10.0))));
+	assert!(!m.intersects(&(m.se + Coord::new(10.0, -10.0)),
+			      &(m.se + Coord::new(20.0, -20.0))));
+    }
+
+    #[test]
+    fn nodata() {
+	let mut samples = vec![2.0; 100];
+	samples[55] = -9999.0;
+	samples[11] = f32::NAN;
+	let mut m = Map::from_samples("m", Coord::new(100.0, 6789745.0),
+				      Coord::new(10.0, 10.0), 10, samples);
+	m.nodata = Some(-9999.0);
+
+	for (e, n) in [(155.0, 6789690.0), (115.0, 6789730.0)] {
+	    let c = Coord::new(e, n);
+	    let r = m.lookup_with_gradient_interpolated(&c, Interpolation::Bilinear);
+	    assert_eq!(r.unwrap_err().downcast_ref::<Error>(),
+		       Some(&Error::NoData(c, String::from("m"))));
+	}
+
+	let c = Coord::new(175.0, 6789710.0);
+	assert_eq!(m.lookup_many(&[c], Interpolation::Bilinear).unwrap()[0],
+		   Ok((2.0, 0.0, 0.0)));
+
+	// A valid cell next to NoData keeps its height, and the gradient is
+	// taken from the valid side
+	let c = Coord::new(145.0, 6789690.0);
+	assert_eq!(m.lookup(&c).unwrap(), 2.0);
+	assert_eq!(m.lookup_with_gradient(&c).unwrap(), (2.0, 0.0, 0.0));
+	assert_eq!(m.lookup_with_gradient_interpolated(
+	    &Coord::new(145.0, 6789695.0), Interpolation::Bilinear).unwrap().0,
+		   2.0);
+    }
+
+    #[test]
+    fn quantized() {
+	let mut samples: Vec<f32> = (0..100).map(|v| 1200.0 + v as f32*0.37)
+	    .collect();
+	samples[55] = -9999.0;
+	let nw = Coord::new(100.0, 6789745.0);
+	let delta = Coord::new(10.0, 10.0);
+	let mut f = Map::from_samples("f", nw, delta, 10, samples.clone());
+	f.nodata = Some(-9999.0);
+	let mut q = Map::from_samples("q", nw, delta, 10, samples.clone());
+	q.nodata = Some(-9999.0);
+	q.im.write().unwrap().set_samples(
+	    0, Samples::store(samples, SampleStorage::Quantized, q.nodata));
+
+	assert_eq!(q.image_bytes()*2, f.image_bytes());
+	assert!(q.max_error() > 0.0 && q.max_error() < 0.001);
+	assert_eq!(f.max_error(), 0.0);
+
+	for (e, n) in [(133.0, 6789722.0), (187.5, 6789670.0)] {
+	    let c = Coord::new(e, n);
+	    let a = f.lookup_with_gradient_interpolated(
+		&c, Interpolation::Bilinear).unwrap();
+	    let b = q.lookup_with_gradient_interpolated(
+		&c, Interpolation::Bilinear).unwrap();
+	    assert!((a.0 - b.0).abs() <= q.max_error());
+	    assert!((a.1 - b.1).abs() < 1e-3 && (a.2 - b.2).abs() < 1e-3);
+	}
+
+	let c = Coord::new(155.0, 6789690.0);
+	assert_eq!(q.lookup(&c).unwrap_err().downcast_ref::<Error>(),
+		   Some(&Error::NoData(c, String::from("q"))));
     }
 
     #[test]
-    fn coord_to_hash() {
-	assert_eq!(Map::coord_to_hash(&Coord::new(100.0, 6789745.0)), 7790240);
+    fn quantized_from_cache() {
+	let dir = TestDir::new("quantized");
+	fs::write(dir.file("m.tif"), b"source").unwrap();
+	let folder = Arc::new(MapFolder::new(&dir.map_dir()));
+	let cache = RasterCache::new(&dir.file("cache"));
+
+	let samples: Vec<f32> = (0..100).map(|v| 1200.0 + v as f32*0.37)
+	    .collect();
+	cache.write("m.tif", &dir.path().join("m.tif"), 10, 10, &samples)
+	    .unwrap();
+	let m = Map::from_samples("m.tif", Coord::new(100.0, 6789745.0),
+				  Coord::new(10.0, 10.0), 10, samples);
+	let float_bytes = m.image_bytes();
+	m.unload_image();
+
+	// The image is quantized from the cache file, not read from the map
+	m.load_window((0, 0, 9, 9), BlockSize::Whole, SampleStorage::Quantized,
+		      &folder, Some(&cache), None).unwrap();
+	assert_eq!(m.image_bytes()*2, float_bytes);
+	let h = m.lookup(&Coord::new(135.0, 6789720.0)).unwrap();
+	assert!((h - (1200.0 + 23.0*0.37)).abs() <= m.max_error());
     }
 
     #[test]
-    fn hashes() {
-	let m = Map::new("testdata/6700_4_10m_z33.tif", "", None).unwrap();
-	let h = m.hashes();
-	let s = HashSet::from(
-            [7730294, 7140317, 7580295, 7600324, 7930241, 7340239, 7900279,
-	     7530327, 7270323, 7500290, 7910264, 7360310, 7430290, 7260294,
-	     7290321, 7540290, 7060258, 7670242, 7380283, 7420269, 7140321]
-        );
-	// panic!("Hashes returned: {}", h.iter().map( |id| id.to_string() + ",").collect::<String>());
-	assert!(s.is_subset(&h));
+    fn raster_type() {
+	let tie = Coord::new(100000.0, 6800000.0);
+	let delta = Coord::new(10.0, 10.0);
+	let samples: Vec<f32> = (0..100).map(|v| v as f32).collect();
+	let corner = |raster_type| {
+	    GeoTransform::from_tie_point(0.0, 0.0, (100000.0, 6800000.0),
+					 (10.0, 10.0))
+		.to_area(raster_type).apply(0.0, 0.0)
+	};
+
+	// The tie point is the corner of the first pixel
+	let nw = corner(RasterType::PixelIsArea);
+	let m = Map::from_samples("m", nw, delta, 10, samples.clone());
+	assert_eq!(m.pixel(&tie), (0.0, 0.0));
+	let c = tie + Coord::new(15.0, -5.0);
+	assert_eq!(m.lookup_with_gradient_interpolated(
+	    &c, Interpolation::Bilinear).unwrap().0, 1.0);
+
+	// The tie point is the centre of the first pixel
+	let nw = corner(RasterType::PixelIsPoint);
+	let m = Map::from_samples("m", nw, delta, 10, samples);
+	assert_eq!(m.pixel(&tie), (0.5, 0.5));
+	let c = tie + Coord::new(10.0, 0.0);
+	assert_eq!(m.lookup_with_gradient_interpolated(
+	    &c, Interpolation::Bilinear).unwrap().0, 1.0);
+	assert_eq!(m.pixel_centre(0, 0), tie);
+    }
+
+    #[test]
+    fn new_from_point_registered() {
+	// testdata/plane_point.tif is testdata/plane.tif with the tie point
+	// (100000, 6800100) at the centre of the first pixel
+	let m = Map::new(&folder(), "testdata/plane_point.tif", "", None)
+	    .unwrap();
+	assert_eq!(m.raster_type, RasterType::PixelIsPoint);
+	assert_eq!(m.nw, Coord::new(99995.0, 6800105.0));
+	assert_eq!(m.se, Coord::new(100095.0, 6800005.0));
+	assert_eq!(m.lookup(&Coord::new(100010.0, 6800090.0)).unwrap(), 111.0);
+    }
+
+    #[test]
+    fn rotated() {
+	// Raster x runs north and y runs east, with samples h = x + 10*y
+	let samples: Vec<f32> = (0..100).map(|v| ((v%10) + 10*(v/10)) as f32)
+	    .collect();
+	let mut m = Map::from_samples("m", Coord::new(0.0, 0.0),
+				      Coord::new(10.0, 10.0), 10, samples);
+	let t = GeoTransform([100.0, 0.0, 10.0, 200.0, 10.0, 0.0]);
+	(m.nw, m.se) = t.bounds(10, 10);
+	m.transform = Some(t);
+	assert_eq!((m.nw, m.se), (Coord::new(100.0, 300.0),
+				  Coord::new(200.0, 200.0)));
+
+	// Pixel (3, 7) is 30 m north and 70 m east of the corner
+	let c = Coord::new(175.0, 235.0);
+	assert!(m.contains(&c));
+	assert_eq!(m.pixel(&c), (3.5, 7.5));
+	assert_eq!(m.pixel_centre(3, 7), c);
+	assert_eq!(m.lookup(&c).unwrap(), 73.0);
+	assert!(!m.contains(&Coord::new(175.0, 195.0)));
+
+	let (h, de, dn) = m.lookup_with_gradient_interpolated(
+	    &Coord::new(150.0, 250.0), Interpolation::Bilinear).unwrap();
+	assert_eq!((h, de, dn), (49.5, 1.0, 0.1));
+    }
+
+    #[test]
+    fn pixel_centre() {
+	let m = Map::from_samples("m", Coord::new(100.0, 6789745.0),
+				  Coord::new(10.0, 10.0), 10, vec![0.0; 100]);
+	let c = m.pixel_centre(3, 7);
+	assert!(m.contains(&c));
+	let (px, py) = m.pixel(&c);
+	assert_eq!((px.floor(), py.floor()), (3.0, 7.0));
+	assert!(!m.contains(&m.pixel_centre(-1, 7)));
     }
 
     #[test]
     fn load_image() {
-	let m = Map::new("testdata/6700_4_10m_z33.tif", "", None).unwrap();
-	match m.load_image(None) {
+	let m = Map::new(&folder(), "testdata/6700_4_10m_z33.tif", "", None)
+	    .unwrap();
+	match m.load_image(&folder(), None) {
 	    Ok(r) => assert_eq!(r, ()),
 	    Err(err) => panic!("{}", err),
 	}
     }
     
+    #[test]
+    fn unload_image() {
+	let m = Map::new(&folder(), "testdata/6700_4_10m_z33.tif", "", None)
+	    .unwrap();
+	m.load_image(&folder(), None).unwrap();
+	assert_eq!(m.image_bytes(), m.width*m.height*4);
+	m.unload_image();
+	assert!(!m.is_loaded());
+	assert_eq!(m.image_bytes(), 0);
+    }
+
+    #[test]
+    fn load_window() {
+	let m = Map::new(&folder(), "testdata/6700_4_10m_z33.tif", "", None)
+	    .unwrap();
+	let c = Coord::new(100.0, 6789745.0);
+	m.load_window(m.window(&c, Interpolation::Nearest),
+		      BlockSize::Fixed(16, 16), SampleStorage::Float, &folder(),
+		      None, None).unwrap();
+	assert!(m.is_resident());
+	assert!(!m.is_loaded());
+	assert!(m.image_bytes() <= 4*16*16*4);
+	assert_eq!(m.lookup(&c).unwrap(), 645.61273);
+	assert!(m.dataset.lock().unwrap().is_some());
+
+	// The file is kept open until the whole image is loaded
+	m.load_image(&folder(), None).unwrap();
+	assert!(m.is_loaded());
+	assert!(m.dataset.lock().unwrap().is_none());
+    }
+
     #[test]
     fn lookup() {
-	let m = Map::new("testdata/6700_4_10m_z33.tif", "", None).unwrap();
+	let m = Map::new(&folder(), "testdata/6700_4_10m_z33.tif", "", None)
+	    .unwrap();
 	match m.lookup(&Coord::new(100.0, 6789745.0)) {
 	    Ok(r)    => assert_eq!(645.61273, r),
 	    Err(err) => panic!("{}", err),
 	}
     }
 
+    #[test]
+    fn lookup_bilinear() {
+	let m = Map::new(&folder(), "testdata/6700_4_10m_z33.tif", "", None)
+	    .unwrap();
+	m.load_image(&folder(), None).unwrap();
+	let c = Coord::new(100.0, 6789745.0);
+	let h = m.lookup_interpolated(&c, Interpolation::Bilinear).unwrap();
+	let (hg, _, _) = m.lookup_with_gradient_interpolated(
+	    &c, Interpolation::Bilinear).unwrap();
+	assert_eq!(h, hg);
+	// Bilinear height is within the range of the neighbouring samples
+	assert!((h - m.lookup(&c).unwrap()).abs() < 10.0);
+    }
+
     #[test]
     fn lookup_failure() {
-	let m = Map::new("testdata/6700_4_10m_z33.tif", "", None).unwrap();
+	let m = Map::new(&folder(), "testdata/6700_4_10m_z33.tif", "", None)
+	    .unwrap();
 	match m.lookup(&Coord::new(-100000.0, 6789745.0)) {
 	    Ok(r)    => assert_eq!(645.61273, r),
 	    Err(err) => assert_eq!(
@@ -268,7 +913,8 @@ mod tests {
 
     #[test]
     fn lookup_with_gradient() {
-	let m = Map::new("testdata/6700_4_10m_z33.tif", "", None).unwrap();
+	let m = Map::new(&folder(), "testdata/6700_4_10m_z33.tif", "", None)
+	    .unwrap();
 	match m.lookup_with_gradient(&Coord::new(100.0, 6789745.0)) {
 	    Ok(r)    => assert_eq!((645.61273, 0.20289306, -0.6372711), r),
 	    Err(err) => panic!("{}", err),
@@ -1,114 +1,107 @@
-use std::fs;
-use std::process::Command;
+use crate::errors::*;
 
-use std::collections::HashMap;
 use lazy_static::lazy_static;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 lazy_static! {
-    pub static ref MAPFOLDER: MapFolder = MapFolder::new();
+    // Map folder of the functions below, and of atlases created without a
+    // map folder
+    pub static ref MAPFOLDER: Arc<MapFolder> = Arc::new(MapFolder::default());
 }
 
-// FIXME: Create an Atlas::flush_maps() method which also unmounts the
-// map directories
+// Zip files are no longer mounted, so there is nothing to unmount. Kept for
+// compatibility.
 pub fn unmount_all_maps() {
-    MAPFOLDER.unmount_all()
 }
 
 pub fn set_map_dir(dir: &str) {
     MAPFOLDER.set_dir(dir)
 }
 
-pub fn map_dir() -> String {
-    MAPFOLDER.get_map_dir()
-}
-
+/*
+Directory holding the maps, zip files and index files of a data set. Each
+atlas references a map folder, so that several data sets, e.g. DTM and DOM,
+can be used in one process.
+ */
+#[derive(Default)]
 pub struct MapFolder {
-    mounts: Mutex<HashMap<String, bool>>,
     map_dir: Mutex<Option<String>>,
 }
 
 impl MapFolder {
-    pub fn new() -> Self {
-	Self {
-	    mounts: Mutex::new(HashMap::new()),
-            map_dir: Mutex::new(None),
-	}
+    // Map folder in directory dir, which must end with a slash
+    pub fn new(dir: &str) -> Self {
+        let s = Self::default();
+        s.set_dir(dir);
+        s
     }
 
     pub fn set_dir(&self, dir: &str) {
         self.map_dir.lock().unwrap().replace(dir.to_string());
     }
 
-    pub fn get_map_dir(&self) -> String {
-        let guard = self.map_dir.lock().unwrap();
-        if let Some(d) = guard.as_ref() {
-            return d.clone();
-        }
-        else {
-            panic!("Map dir is not loaded");
-        }
+    pub fn dir(&self) -> Result<String> {
+        self.map_dir.lock().unwrap().clone()
+            .ok_or_else(|| Error::MapDirNotSet.into())
     }
 
-    pub fn is_mounted(&self, filename: &str) -> bool {
-	self.mounts.lock().unwrap().contains_key(filename)
-    }
-    
-    pub fn register(&self, filename: &str) {
-	self.mounts.lock().unwrap().insert(String::from(filename), true);
+    // Path of file within the map folder
+    pub fn path(&self, file: &str) -> Result<String> {
+        Ok(format!("{}{}", self.dir()?, file))
     }
 
-    pub fn unmount_all(&self) {
-	// Unmount all registered mount directories
-	for k in self.mounts.lock().unwrap().keys() {
-	    let absdir = format!("{}{}.dir", map_dir(), &k);
+    // Path of zip file in GDAL's /vsizip/ file system, where it can be
+    // read as a directory
+    pub fn zip_path(&self, zipfile: &str) -> Result<String> {
+        Ok(format!("/vsizip/{}", self.path(zipfile)?))
+    }
 
-	    Command::new("/usr/bin/fusermount")
-		.arg("-u")
-		.arg(&absdir)
-		.output()
-		.expect("failed to execute process");
+    /*
+    Path GDAL reads map fname from. Maps in zip files are named
+    <zipfile>.dir/<entry>, after the directory zip files used to be mounted
+    on, so that index files stay valid. They are read from within the zip
+    file.
+     */
+    pub fn map_path(&self, fname: &str, zipfile: &str) -> Result<String> {
+        if zipfile.is_empty() {
+            return self.path(fname);
+        }
 
-	    // Remove directory
-	    fs::remove_dir(&absdir).unwrap();
-	}
+        let entry = fname.strip_prefix(zipfile)
+            .and_then(|f| f.strip_prefix(".dir/"))
+            .unwrap_or(fname);
 
-	self.mounts.lock().unwrap().clear();
+        Ok(format!("{}/{}", self.zip_path(zipfile)?, entry))
     }
 }
 
-pub struct ZipMount {
-    pub directory: String,
-}
+#[cfg(test)]
+mod tests {
+    use crate::errors::Error;
+    use crate::mapfolder::MapFolder;
+
+    #[test]
+    fn dir() {
+        let a = MapFolder::new("/data/dtm/");
+        let b = MapFolder::default();
+        assert_eq!(a.path("atlas.bin").unwrap(), "/data/dtm/atlas.bin");
+        assert_eq!(b.dir().unwrap_err().downcast_ref::<Error>(),
+                   Some(&Error::MapDirNotSet));
+
+        b.set_dir("/data/dom/");
+        assert_eq!(b.dir().unwrap(), "/data/dom/");
+        assert_eq!(a.dir().unwrap(), "/data/dtm/");
+    }
 
-impl ZipMount {
-    pub fn new(filename: &str) -> Self {
-	// Check that file is zip file
-	if !(filename.ends_with(".zip")) {
-	    panic!("No zip file");
-	}
-
-	let directory = format!("{}.dir/", filename);
-	let absdir = format!("{}{}.dir", map_dir(), filename);
-
-	if !MAPFOLDER.is_mounted(filename) {
-	    // Only mount first time.
-	    // Create directory <zipfile>.dir
-	    fs::create_dir_all(&absdir).unwrap();
-
-	    // fuse-zip -r <zipfile> <zipfile>.dir
-	    Command::new("/usr/bin/fuse-zip")
-		.arg("-r")
-		.arg(format!("{}{}", map_dir(), &filename))
-		.arg(absdir)
-		.output()
-		.expect("failed to execute process");
-
-	    MAPFOLDER.register(filename);
-	}
-
-	Self {
-	    directory: directory.clone(),
-	}
+    #[test]
+    fn map_path() {
+        let f = MapFolder::new("/data/dtm/");
+        assert_eq!(f.map_path("dtm1_33_122_117.tif", "").unwrap(),
+                   "/data/dtm/dtm1_33_122_117.tif");
+        assert_eq!(f.map_path("Hamar 2017.zip.dir/dtm1_33_122_117.tif",
+                              "Hamar 2017.zip").unwrap(),
+                   "/vsizip//data/dtm/Hamar 2017.zip/dtm1_33_122_117.tif");
+        assert_eq!(MapFolder::new("").map_path("a.zip.dir/b/c.tif", "a.zip")
+                   .unwrap(), "/vsizip/a.zip/b/c.tif");
     }
 }
@@ -0,0 +1,79 @@
+use crate::map::Map;
+
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::sync::Arc;
+
+/*
+Policy for choosing between maps overlapping at a coordinate. Ties are
+broken by resolution, finest first, and then by the order the maps were
+added to the atlas, so that lookups are deterministic.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OverlapPolicy {
+    // Use the map with the finest resolution
+    #[default]
+    FinestResolution,
+    // Use the map from the most recent survey. Maps with an unknown survey
+    // year come last.
+    NewestSurvey,
+    // Use maps from the named project before other maps
+    PreferProject(String),
+    // Average the values of all maps covering the coordinate
+    Average,
+}
+
+impl OverlapPolicy {
+    /*
+    Order the candidate maps for a coordinate by preference. The candidates
+    must be given finest resolution first. With Average, the order only
+    decides which map to report the resolution of.
+     */
+    pub fn order(&self, maps: &mut [Arc<Map>]) {
+        match self {
+            OverlapPolicy::FinestResolution | OverlapPolicy::Average => {},
+            OverlapPolicy::NewestSurvey => {
+                maps.sort_by_key(|m| Reverse(m.survey_year()));
+            },
+            OverlapPolicy::PreferProject(project) => {
+                maps.sort_by_key(|m| m.project() != project);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coord::Coord;
+    use crate::map::Map;
+    use crate::policy::OverlapPolicy;
+    use crate::testmaps::survey_map;
+    use std::sync::Arc;
+
+    #[test]
+    fn order() {
+        let map = |fname, zipfile| {
+            Arc::new(survey_map(fname, zipfile, Coord::new(0.0, 100.0), 10.0,
+                                10, 0.0))
+        };
+        let maps = vec![map("a", "NDH Hamar 2pkt 2015.zip"),
+                        map("b", ""),
+                        map("c", "NDH Hamar 5pkt 2019.zip")];
+        let names = |v: &[Arc<Map>]| -> Vec<String> {
+            v.iter().map(|m| m.fname.clone()).collect()
+        };
+
+        let mut v = maps.clone();
+        OverlapPolicy::FinestResolution.order(&mut v);
+        assert_eq!(names(&v), vec!["a", "b", "c"]);
+
+        let mut v = maps.clone();
+        OverlapPolicy::NewestSurvey.order(&mut v);
+        assert_eq!(names(&v), vec!["c", "a", "b"]);
+
+        let mut v = maps.clone();
+        OverlapPolicy::PreferProject("NDH Hamar 5pkt 2019".to_string())
+            .order(&mut v);
+        assert_eq!(names(&v), vec!["c", "a", "b"]);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::atlas::Atlas;
+use crate::coord::Coord;
+
+use crossbeam_channel::{unbounded, Sender, Receiver};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+// Hints of where lookups are going to be done
+#[derive(Clone, Debug)]
+pub enum PrefetchHint {
+    // Maps along a planned path, loaded in the order of the path
+    Path(Vec<Coord>),
+    // All maps within a rectangle, given by its north west and south east
+    // corners
+    Area(Coord, Coord),
+    // Maps ahead of a moving position. The heading is the angle of movement
+    // in radians, counterclockwise from east, as for Coord::from_polar. The
+    // distance is how far ahead to load.
+    Motion { position: Coord, heading: f64, distance: f64 },
+}
+
+// Number of hints received but not yet processed
+struct Pending {
+    count: Mutex<usize>,
+    done: Condvar,
+    // Set when a worker panicked. The atlas may then be inconsistent, so
+    // no more hints are processed.
+    failed: Mutex<bool>,
+}
+
+// Marks a hint as processed when dropped, also when the worker panics
+struct Processed<'a> {
+    pending: &'a Pending,
+    hints: &'a Receiver<PrefetchHint>,
+}
+
+impl Drop for Processed<'_> {
+    fn drop(&mut self) {
+        let mut count = self.pending.count.lock().unwrap();
+        *count -= 1;
+
+        if thread::panicking() {
+            // Discard the queued hints, as there may be no workers left
+            *self.pending.failed.lock().unwrap() = true;
+            while self.hints.try_recv().is_ok() {
+                *count -= 1;
+            }
+        }
+
+        if *count == 0 {
+            self.pending.done.notify_all();
+        }
+    }
+}
+
+/*
+Background loading of map images. Hints are handed to a pool of worker
+threads, which load the maps of each hint into the atlas, so that later
+lookups find them already loaded. Loading respects the memory budget of the
+atlas, and failures are reported on the message channel of the atlas.
+
+The workers are stopped when the prefetcher is dropped.
+ */
+pub struct Prefetcher {
+    hints: Option<Sender<PrefetchHint>>,
+    pending: Arc<Pending>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Prefetcher {
+    pub fn new(atlas: Arc<Atlas>, threads: usize) -> Self {
+        let (tx, rx) = unbounded();
+        let pending = Arc::new(Pending {
+            count: Mutex::new(0),
+            done: Condvar::new(),
+            failed: Mutex::new(false),
+        });
+
+        let workers = (0..threads.max(1)).map(|_| {
+            let atlas = Arc::clone(&atlas);
+            let rx: Receiver<PrefetchHint> = rx.clone();
+            let pending = Arc::clone(&pending);
+
+            thread::spawn(move || {
+                while let Ok(hint) = rx.recv() {
+                    let _processed = Processed {
+                        pending: &pending,
+                        hints: &rx,
+                    };
+
+                    if !*pending.failed.lock().unwrap() {
+                        Self::prefetch(&atlas, &hint);
+                    }
+                }
+            })
+        }).collect();
+
+        Self {
+            hints: Some(tx),
+            pending,
+            workers,
+        }
+    }
+
+    fn prefetch(atlas: &Atlas, hint: &PrefetchHint) {
+        let maps = match hint {
+            PrefetchHint::Path(path) => atlas.maps_along(path),
+            PrefetchHint::Area(nw, se) => atlas.maps_in_area(nw, se),
+            PrefetchHint::Motion { position, heading, distance } => {
+                let ahead = *position + Coord::from_polar(*distance, *heading);
+                atlas.maps_along(&[*position, ahead])
+            },
+        };
+
+        atlas.prefetch_maps(&maps);
+    }
+
+    // Queue a hint for the workers. Hints are ignored after a worker
+    // panicked.
+    pub fn hint(&self, hint: PrefetchHint) {
+        let mut count = self.pending.count.lock().unwrap();
+        if *self.pending.failed.lock().unwrap() {
+            return;
+        }
+
+        *count += 1;
+        self.hints.as_ref().unwrap().send(hint).unwrap();
+    }
+
+    // Whether a worker panicked, so that the hints are no longer processed
+    pub fn failed(&self) -> bool {
+        *self.pending.failed.lock().unwrap()
+    }
+
+    // Number of hints not yet processed
+    pub fn pending(&self) -> usize {
+        *self.pending.count.lock().unwrap()
+    }
+
+    // Block until all queued hints are processed
+    pub fn wait(&self) {
+        let mut count = self.pending.count.lock().unwrap();
+        while *count > 0 {
+            count = self.pending.done.wait(count).unwrap();
+        }
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        // Closing the channel stops the workers when the queue is empty
+        self.hints = None;
+
+        for w in self.workers.drain(..) {
+            let _ = w.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::atlas::Atlas;
+    use crate::coord::Coord;
+    use crate::mapfolder::MapFolder;
+    use crate::prefetch::{Prefetcher, PrefetchHint};
+    use crate::testdir::TestDir;
+    use crate::testmaps::{cache_dir, cached_map};
+    use std::sync::Arc;
+
+    #[test]
+    fn hints() {
+        let p = Prefetcher::new(Arc::new(Atlas::new_mockup()), 2);
+        let c = Coord::from("N6851889.09E146005.17");
+
+        p.hint(PrefetchHint::Path(vec![c, c + Coord::new(1000.0, 0.0)]));
+        p.hint(PrefetchHint::Area(c, c + Coord::new(1000.0, -1000.0)));
+        p.hint(PrefetchHint::Motion { position: c, heading: 0.5,
+                                      distance: 2000.0 });
+        p.wait();
+        assert_eq!(p.pending(), 0);
+    }
+
+    #[test]
+    fn load_area() {
+        // Two unloaded maps side by side, loading from the raster cache
+        let dir = TestDir::new("prefetch");
+        let maps = [100000.0, 100100.0].iter().enumerate().map(|(i, e)| {
+            cached_map(&dir, &format!("m{}.tif", i),
+                       Coord::new(*e, 6800100.0), 10.0, 10, 1.0)
+        }).collect();
+        let mut a = Atlas::from_maps(maps, None);
+        a.set_map_folder(Arc::new(MapFolder::new(&dir.map_dir())));
+        a.set_cache_dir(Some(&cache_dir(&dir)));
+        let a = Arc::new(a);
+
+        let p = Prefetcher::new(Arc::clone(&a), 2);
+        p.hint(PrefetchHint::Area(Coord::new(100010.0, 6800090.0),
+                                  Coord::new(100050.0, 6800010.0)));
+        p.wait();
+
+        let inside = a.lookup_maps(&Coord::new(100050.0, 6800050.0)).unwrap();
+        let outside = a.lookup_maps(&Coord::new(100150.0, 6800050.0))
+            .unwrap();
+        assert!(inside[0].is_loaded());
+        assert!(!outside[0].is_loaded());
+        assert_eq!(a.memory_usage().maps, 1);
+    }
+}
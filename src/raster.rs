@@ -0,0 +1,359 @@
+use memmap2::Mmap;
+
+/*
+Height samples of a map, divided into a grid of blocks. Each block is loaded
+independently, so that sparse lookups only need to read the blocks they
+touch. A raster loaded as a whole has a single block covering the image.
+ */
+
+// Size of the blocks map images are loaded in
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum BlockSize {
+    // Load the whole image at once
+    #[default]
+    Whole,
+    // Use the block size of the GeoTIFF file
+    Native,
+    // Load windows of the given width and height
+    Fixed(usize, usize),
+}
+
+// How the samples of loaded images are stored in memory
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SampleStorage {
+    // Four byte floats, as read from the file
+    #[default]
+    Float,
+    // Two byte integers, scaled to the range of the values of each block
+    Quantized,
+}
+
+// Code of NoData samples in quantized blocks
+const NODATA_CODE: u16 = u16::MAX;
+
+/*
+Samples scaled to two byte integers, sample = offset + code*scale, with the
+codes spread over the range of the values of the block. NoData samples, and
+NaN, are kept as a separate code, and are decoded as NaN.
+ */
+pub struct Quantized {
+    codes: Vec<u16>,
+    offset: f32,
+    scale: f32,
+    // Largest difference between a decoded sample and the original
+    max_error: f32,
+}
+
+impl Quantized {
+    pub fn new(samples: &[f32], nodata: Option<f32>) -> Self {
+        let valid = |v: &f32| v.is_finite() && Some(*v) != nodata;
+
+        let (min, max) = samples.iter().copied().filter(valid)
+            .fold((f32::INFINITY, f32::NEG_INFINITY),
+                  |(lo, hi), v| (lo.min(v), hi.max(v)));
+        let offset = if min.is_finite() { min } else { 0.0 };
+        let scale = if max > min {
+            (max - min)/(NODATA_CODE - 1) as f32
+        }
+        else {
+            0.0
+        };
+
+        let mut q = Self {
+            codes: Vec::with_capacity(samples.len()),
+            offset,
+            scale,
+            max_error: 0.0,
+        };
+
+        for v in samples {
+            let code = if !valid(v) {
+                NODATA_CODE
+            }
+            else if scale > 0.0 {
+                (((v - offset)/scale).round() as u16).min(NODATA_CODE - 1)
+            }
+            else {
+                0
+            };
+            q.codes.push(code);
+
+            if code != NODATA_CODE {
+                q.max_error = q.max_error.max((q.decode(code) - v).abs());
+            }
+        }
+
+        q
+    }
+
+    fn decode(&self, code: u16) -> f32 {
+        if code == NODATA_CODE {
+            f32::NAN
+        }
+        else {
+            self.offset + code as f32*self.scale
+        }
+    }
+}
+
+// Samples of a block, row by row
+pub enum Samples {
+    Owned(Vec<f32>),
+    // Floats following a header of the given length in a memory mapped file
+    Mapped(Mmap, usize),
+    Quantized(Quantized),
+}
+
+impl Samples {
+    // Samples read from a file, stored as given. NoData samples are kept as
+    // NoData.
+    pub fn store(data: Vec<f32>, storage: SampleStorage, nodata: Option<f32>)
+                 -> Self {
+        match storage {
+            SampleStorage::Float => Samples::Owned(data),
+            SampleStorage::Quantized =>
+                Samples::Quantized(Quantized::new(&data, nodata)),
+        }
+    }
+
+    // The samples with the given storage. Quantized samples stay quantized.
+    pub fn with_storage(self, storage: SampleStorage, nodata: Option<f32>)
+                        -> Self {
+        if storage == SampleStorage::Quantized &&
+            let Some(v) = self.as_slice() {
+            return Samples::Quantized(Quantized::new(v, nodata));
+        }
+
+        self
+    }
+
+    // The samples, unless they are quantized
+    pub fn as_slice(&self) -> Option<&[f32]> {
+        match self {
+            Samples::Owned(v) => Some(v),
+            Samples::Mapped(m, offset) => {
+                // The map is page aligned, and the header length is a
+                // multiple of four. Any bit pattern is a valid f32.
+                let (prefix, floats, _) = unsafe { m[*offset..].align_to() };
+                assert!(prefix.is_empty(), "Unaligned raster cache file");
+                Some(floats)
+            },
+            Samples::Quantized(_) => None,
+        }
+    }
+
+    pub fn get(&self, i: usize) -> Option<f32> {
+        match self {
+            Samples::Quantized(q) => q.codes.get(i).map(|c| q.decode(*c)),
+            _ => self.as_slice()?.get(i).copied(),
+        }
+    }
+
+    // Number of bytes held by the samples
+    pub fn bytes(&self) -> usize {
+        match self {
+            Samples::Quantized(q) => q.codes.len()*std::mem::size_of::<u16>(),
+            _ => self.as_slice().map_or(0, |s| s.len())*
+                std::mem::size_of::<f32>(),
+        }
+    }
+
+    // Largest difference between a stored sample and the value it was made
+    // from
+    pub fn max_error(&self) -> f32 {
+        match self {
+            Samples::Quantized(q) => q.max_error,
+            _ => 0.0,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Raster {
+    width: usize,
+    height: usize,
+    block_width: usize,
+    block_height: usize,
+    // Samples of each block. None if the block is not loaded.
+    blocks: Vec<Option<Samples>>,
+}
+
+impl Raster {
+    pub fn new(width: usize, height: usize, block_width: usize,
+               block_height: usize) -> Self {
+        let block_width = block_width.clamp(1, width.max(1));
+        let block_height = block_height.clamp(1, height.max(1));
+        let n = width.div_ceil(block_width)*height.div_ceil(block_height);
+
+        Self {
+            width,
+            height,
+            block_width,
+            block_height,
+            blocks: (0..n).map(|_| None).collect(),
+        }
+    }
+
+    // Create a raster with a single block holding the whole image
+    pub fn whole(width: usize, height: usize, samples: Samples) -> Self {
+        let mut r = Self::new(width, height, width, height);
+        r.blocks[0] = Some(samples);
+        r
+    }
+
+    fn blocks_x(&self) -> usize {
+        self.width.div_ceil(self.block_width)
+    }
+
+    // Pixel window (x, y, width, height) of block i
+    pub fn block_window(&self, i: usize) -> (usize, usize, usize, usize) {
+        let x = (i % self.blocks_x())*self.block_width;
+        let y = (i / self.blocks_x())*self.block_height;
+
+        (x, y, self.block_width.min(self.width - x),
+         self.block_height.min(self.height - y))
+    }
+
+    /*
+    Return the indices of the blocks overlapping the inclusive pixel window
+    (x0, y0, x1, y1). The window is clipped to the raster.
+     */
+    pub fn blocks_in_window(&self, x0: isize, y0: isize, x1: isize, y1: isize)
+                            -> Vec<usize> {
+        if self.blocks.is_empty() || x1 < 0 || y1 < 0 ||
+            x0 >= self.width as isize || y0 >= self.height as isize {
+            return Vec::new();
+        }
+
+        let bx0 = x0.max(0) as usize/self.block_width;
+        let by0 = y0.max(0) as usize/self.block_height;
+        let bx1 = (x1 as usize).min(self.width - 1)/self.block_width;
+        let by1 = (y1 as usize).min(self.height - 1)/self.block_height;
+
+        let mut v = Vec::new();
+        for by in by0..=by1 {
+            for bx in bx0..=bx1 {
+                v.push(bx + by*self.blocks_x());
+            }
+        }
+
+        v
+    }
+
+    pub fn is_resident(&self, i: usize) -> bool {
+        self.blocks[i].is_some()
+    }
+
+    pub fn is_window_resident(&self, x0: isize, y0: isize, x1: isize,
+                              y1: isize) -> bool {
+        !self.blocks.is_empty() &&
+            self.blocks_in_window(x0, y0, x1, y1).iter()
+            .all(|i| self.is_resident(*i))
+    }
+
+    pub fn set_samples(&mut self, i: usize, samples: Samples) {
+        self.blocks[i] = Some(samples);
+    }
+
+    // Check if all blocks are loaded
+    pub fn is_complete(&self) -> bool {
+        !self.blocks.is_empty() && self.blocks.iter().all(|b| b.is_some())
+    }
+
+    // Check if no blocks are loaded
+    pub fn is_empty(&self) -> bool {
+        self.blocks.iter().all(|b| b.is_none())
+    }
+
+    // Number of bytes held by the loaded blocks, including memory mapped
+    // blocks
+    pub fn bytes(&self) -> usize {
+        self.blocks.iter().flatten().map(|b| b.bytes()).sum()
+    }
+
+    // Largest error of the loaded samples from quantization
+    pub fn max_error(&self) -> f32 {
+        self.blocks.iter().flatten().map(|b| b.max_error())
+            .fold(0.0, f32::max)
+    }
+
+    pub fn clear(&mut self) {
+        for b in self.blocks.iter_mut() {
+            *b = None;
+        }
+    }
+
+    // Value of pixel (x, y), or None if its block is not loaded
+    pub fn value(&self, x: usize, y: usize) -> Option<f32> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+
+        let i = x/self.block_width + (y/self.block_height)*self.blocks_x();
+        let (bx, by, bw, _) = self.block_window(i);
+
+        self.blocks[i].as_ref()?.get(x - bx + (y - by)*bw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raster::{Quantized, Raster, SampleStorage, Samples};
+
+    #[test]
+    fn blocks_in_window() {
+        let r = Raster::new(10, 7, 4, 3);
+        assert_eq!(r.blocks_in_window(0, 0, 0, 0), vec![0]);
+        assert_eq!(r.blocks_in_window(3, 2, 4, 3), vec![0, 1, 3, 4]);
+        assert_eq!(r.blocks_in_window(-5, 6, 20, 20), vec![6, 7, 8]);
+        assert!(r.blocks_in_window(10, 0, 12, 2).is_empty());
+    }
+
+    #[test]
+    fn block_window() {
+        let r = Raster::new(10, 7, 4, 3);
+        assert_eq!(r.block_window(0), (0, 0, 4, 3));
+        assert_eq!(r.block_window(2), (8, 0, 2, 3));
+        assert_eq!(r.block_window(8), (8, 6, 2, 1));
+    }
+
+    #[test]
+    fn value() {
+        let mut r = Raster::new(10, 7, 4, 3);
+        assert_eq!(r.value(9, 6), None);
+
+        r.set_samples(8, Samples::Owned(vec![1.0, 2.0]));
+        assert_eq!(r.value(9, 6), Some(2.0));
+        assert!(r.is_window_resident(8, 6, 9, 6));
+        assert!(!r.is_window_resident(7, 6, 9, 6));
+        assert!(!r.is_complete());
+        assert_eq!(r.bytes(), 8);
+
+        r.clear();
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn quantized() {
+        let data: Vec<f32> = (0..100).map(|v| 512.37 + v as f32*13.11)
+            .chain([-9999.0, f32::NAN]).collect();
+        let q = Quantized::new(&data, Some(-9999.0));
+        let range = 99.0*13.11;
+        assert!(q.max_error > 0.0 && q.max_error <= 0.5*range/65534.0 + 1e-4);
+
+        let mut r = Raster::new(102, 1, 102, 1);
+        r.set_samples(0, Samples::store(data.clone(), SampleStorage::Quantized,
+                                        Some(-9999.0)));
+        assert_eq!(r.bytes(), 102*2);
+        assert_eq!(r.max_error(), q.max_error);
+        for (i, v) in data[..100].iter().enumerate() {
+            assert!((r.value(i, 0).unwrap() - v).abs() <= q.max_error);
+        }
+        assert!(r.value(100, 0).unwrap().is_nan());
+        assert!(r.value(101, 0).unwrap().is_nan());
+
+        // A flat block is exact
+        let q = Quantized::new(&[3.5; 4], None);
+        assert_eq!((q.decode(q.codes[0]), q.max_error), (3.5, 0.0));
+    }
+}
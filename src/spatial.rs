@@ -0,0 +1,161 @@
+use crate::coord::Coord;
+use crate::geometry;
+use crate::map::Map;
+
+use rstar::{RTree, RTreeObject, AABB};
+use std::sync::Arc;
+
+// Footprint of a map in the index. The sequence number is the order the map
+// was added in, which breaks ties between maps of the same resolution.
+struct Footprint {
+    map: Arc<Map>,
+    seq: usize,
+}
+
+impl RTreeObject for Footprint {
+    type Envelope = AABB<[f64; 2]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        AABB::from_corners([self.map.nw.e, self.map.se.n],
+                           [self.map.se.e, self.map.nw.n])
+    }
+}
+
+/*
+Spatial index of map footprints, kept in an R-tree. Queries are exact, and
+work for coordinates anywhere. Maps found by a query are ordered from finest
+to coarsest resolution, and in the order they were added within the same
+resolution.
+ */
+#[derive(Default)]
+pub struct MapIndex {
+    tree: RTree<Footprint>,
+    next: usize,
+}
+
+impl MapIndex {
+    pub fn new(maps: Vec<Arc<Map>>) -> Self {
+        let next = maps.len();
+        let footprints = maps.into_iter().enumerate()
+            .map(|(seq, map)| Footprint { map, seq })
+            .collect();
+
+        Self {
+            tree: RTree::bulk_load(footprints),
+            next,
+        }
+    }
+
+    pub fn insert(&mut self, map: Arc<Map>) {
+        self.tree.insert(Footprint { map, seq: self.next });
+        self.next += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.size() == 0
+    }
+
+    // All maps of the index, in the order they were added
+    pub fn maps(&self) -> Vec<Arc<Map>> {
+        let mut v: Vec<&Footprint> = self.tree.iter().collect();
+        v.sort_by_key(|f| f.seq);
+        v.into_iter().map(|f| Arc::clone(&f.map)).collect()
+    }
+
+    fn sorted(mut v: Vec<&Footprint>) -> Vec<Arc<Map>> {
+        v.sort_by(|a, b| a.map.resolution().total_cmp(&b.map.resolution())
+                  .then(a.seq.cmp(&b.seq)));
+        v.into_iter().map(|f| Arc::clone(&f.map)).collect()
+    }
+
+    // Maps with a footprint containing coord, including its edges
+    pub fn at(&self, coord: &Coord) -> Vec<Arc<Map>> {
+        let p = AABB::from_point([coord.e, coord.n]);
+        Self::sorted(self.tree.locate_in_envelope_intersecting(p).collect())
+    }
+
+    // Maps overlapping the area between the corners nw and se
+    pub fn in_area(&self, nw: &Coord, se: &Coord) -> Vec<Arc<Map>> {
+        let area = AABB::from_corners([nw.e, se.n], [se.e, nw.n]);
+        Self::sorted(self.tree.locate_in_envelope_intersecting(area)
+                     .collect())
+    }
+
+    // Maps crossed by a path, in the order the path enters them
+    pub fn along(&self, path: &[Coord]) -> Vec<Arc<Map>> {
+        let mut ret: Vec<Arc<Map>> = Vec::new();
+
+        let segments: Vec<(Coord, Coord)> = match path {
+            [c] => vec![(*c, *c)],
+            _ => path.windows(2).map(|w| (w[0], w[1])).collect(),
+        };
+
+        for (a, b) in segments {
+            let bbox = AABB::from_corners([a.e, a.n], [b.e, b.n]);
+            let mut crossed: Vec<(f64, &Footprint)> = self.tree
+                .locate_in_envelope_intersecting(bbox)
+                .filter_map(|f| {
+                    geometry::segment_rect_entry(&a, &b, &f.map.nw, &f.map.se)
+                        .map(|t| (t, f))
+                })
+                .collect();
+
+            crossed.sort_by(|(t0, f0), (t1, f1)| t0.total_cmp(t1)
+                            .then(f0.map.resolution()
+                                  .total_cmp(&f1.map.resolution()))
+                            .then(f0.seq.cmp(&f1.seq)));
+
+            for (_, f) in crossed {
+                if !ret.iter().any(|r| Arc::ptr_eq(r, &f.map)) {
+                    ret.push(Arc::clone(&f.map));
+                }
+            }
+        }
+
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coord::Coord;
+    use crate::map::Map;
+    use crate::spatial::MapIndex;
+    use crate::testmaps::flat_map;
+    use std::sync::Arc;
+
+    #[test]
+    fn queries() {
+        // Coordinates far outside of Norway, including negative ones
+        let map = |fname, e, delta| {
+            Arc::new(flat_map(fname, Coord::new(e, 100.0), delta, 10, 0.0))
+        };
+        let mut index = MapIndex::new(vec![map("a", -500.0, 10.0),
+                                           map("b", -400.0, 10.0)]);
+        index.insert(map("c", -500.0, 1.0));
+        assert_eq!(index.maps().len(), 3);
+
+        let names = |v: Vec<Arc<Map>>| -> Vec<String> {
+            v.iter().map(|m| m.fname.clone()).collect()
+        };
+
+        assert_eq!(names(index.at(&Coord::new(-495.0, 95.0))),
+                   vec!["c", "a"]);
+        assert_eq!(names(index.at(&Coord::new(-450.0, 50.0))), vec!["a"]);
+        assert!(index.at(&Coord::new(-450.0, 101.0)).is_empty());
+
+        assert_eq!(names(index.in_area(&Coord::new(-420.0, 50.0),
+                                       &Coord::new(-380.0, 40.0))),
+                   vec!["a", "b"]);
+
+        assert_eq!(names(index.along(&[Coord::new(-250.0, 50.0),
+                                       Coord::new(-600.0, 50.0)])),
+                   vec!["b", "a"]);
+        assert_eq!(names(index.along(&[Coord::new(-350.0, 150.0),
+                                       Coord::new(-350.0, 120.0)])),
+                   Vec::<String>::new());
+        assert_eq!(names(index.along(&[Coord::new(-495.0, 95.0)])),
+                   vec!["c", "a"]);
+        assert_eq!(names(index.maps()), vec!["a", "b", "c"]);
+    }
+}
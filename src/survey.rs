@@ -0,0 +1,116 @@
+use lazy_regex::{regex_captures, regex_is_match};
+use serde::{Deserialize, Serialize};
+
+// Elevation product of a map
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Product {
+    #[default]
+    Unknown,
+    // Terrain model, the ground surface
+    Dtm,
+    // Surface model, including vegetation and buildings
+    Dom,
+}
+
+impl Product {
+    pub fn code(&self) -> u8 {
+        match self {
+            Product::Unknown => 0,
+            Product::Dtm => 1,
+            Product::Dom => 2,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            1 => Product::Dtm,
+            2 => Product::Dom,
+            _ => Product::Unknown,
+        }
+    }
+
+    // Product named in a file name or description, e.g. "dtm1_33_122_117"
+    fn from_name(name: &str) -> Option<Self> {
+        if regex_is_match!(r"(?i)(^|[^a-z])dtm", name) {
+            Some(Product::Dtm)
+        }
+        else if regex_is_match!(r"(?i)(^|[^a-z])dom", name) {
+            Some(Product::Dom)
+        }
+        else {
+            None
+        }
+    }
+}
+
+/*
+Survey a map comes from. Maps downloaded from hoydedata.no are distributed
+in one zip file per project, named after the project, e.g. "NDH Hamar 2pkt
+2017.zip". The project name gives the year of the survey, and the file names
+give the product.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SurveyInfo {
+    // Name of the scanning project, or empty if unknown
+    pub project: String,
+    pub year: Option<u32>,
+    pub product: Product,
+}
+
+impl SurveyInfo {
+    fn year_in(name: &str) -> Option<u32> {
+        regex_captures!(r"(?:^|[^0-9])((?:19|20)[0-9]{2})(?:[^0-9]|$)", name)
+            .and_then(|(_, y)| y.parse().ok())
+    }
+
+    /*
+    Survey of a map, from the names of the map file and the zip file it is
+    distributed in, and the ImageDescription and DateTime tags of the
+    GeoTIFF file. The names take precedence over the tags, since the tags
+    are often set by the export rather than by the survey.
+     */
+    pub fn new(fname: &str, zipfile: &str, description: Option<&str>,
+               datetime: Option<&str>) -> Self {
+        let zipname = zipfile.rsplit('/').next().unwrap_or("");
+        let project = zipname.strip_suffix(".zip").unwrap_or(zipname);
+
+        let year = Self::year_in(project)
+            .or_else(|| description.and_then(Self::year_in))
+            .or_else(|| datetime.and_then(Self::year_in));
+
+        let product = Product::from_name(fname)
+            .or_else(|| Product::from_name(project))
+            .or_else(|| description.and_then(Product::from_name))
+            .unwrap_or_default();
+
+        Self {
+            project: project.to_string(),
+            year,
+            product,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::survey::{Product, SurveyInfo};
+
+    #[test]
+    fn from_names() {
+        let s = SurveyInfo::new("NDH Hamar 2pkt 2017.zip.dir/dtm1/data/dtm1_33_122_117.tif",
+                                "NDH Hamar 2pkt 2017.zip", None, None);
+        assert_eq!(s.project, "NDH Hamar 2pkt 2017");
+        assert_eq!(s.year, Some(2017));
+        assert_eq!(s.product, Product::Dtm);
+
+        let s = SurveyInfo::new("dom1_33_122_117.tif", "", None,
+                                Some("2021:05:03 10:00:00"));
+        assert_eq!(s.project, "");
+        assert_eq!(s.year, Some(2021));
+        assert_eq!(s.product, Product::Dom);
+
+        let s = SurveyInfo::new("6700_4_10m_z33.tif", "", None, None);
+        assert_eq!(s, SurveyInfo::default());
+        assert_eq!(Product::from_code(Product::Dom.code()), Product::Dom);
+    }
+}
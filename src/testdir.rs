@@ -0,0 +1,48 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/*
+Temporary directory for tests. The name is unique to the process, the time
+and the directory, so that tests and test runs do not share files. The
+directory is removed when dropped, also when the test fails.
+ */
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!(
+            "hoydedata-{}-{}-{}-{}", name, std::process::id(), nanos,
+            COUNTER.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(&path).unwrap();
+
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Path of file within the directory
+    pub fn file(&self, name: &str) -> String {
+        self.path.join(name).to_str().unwrap().to_string()
+    }
+
+    // The directory as a map directory, ending with a slash
+    pub fn map_dir(&self) -> String {
+        format!("{}/", self.path.to_str().unwrap())
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,45 @@
+use crate::cache::RasterCache;
+use crate::coord::Coord;
+use crate::map::Map;
+use crate::survey::SurveyInfo;
+use crate::testdir::TestDir;
+use std::fs;
+
+// Square map of width pixels of delta metres, with its north west corner at
+// nw and height h everywhere
+pub fn flat_map(fname: &str, nw: Coord, delta: f64, width: usize, h: f32)
+                -> Map {
+    Map::from_samples(fname, nw, Coord::new(delta, delta), width,
+                      vec![h; width*width])
+}
+
+// As flat_map, for a map from the survey of the given zip file
+pub fn survey_map(fname: &str, zipfile: &str, nw: Coord, delta: f64,
+                  width: usize, h: f32) -> Map {
+    let mut m = flat_map(fname, nw, delta, width, h);
+    m.zipfile = zipfile.to_string();
+    m.survey = SurveyInfo::new(fname, zipfile, None, None);
+    m
+}
+
+// Directory of the raster cache holding the images of cached_map
+pub fn cache_dir(dir: &TestDir) -> String {
+    dir.file("cache")
+}
+
+/*
+Unloaded flat map, with its image in the raster cache at cache_dir(dir). With
+dir as map folder, the image is loaded from the cache, so that loading can be
+tested without GeoTIFF files. The source file is only a placeholder.
+ */
+pub fn cached_map(dir: &TestDir, fname: &str, nw: Coord, delta: f64,
+                  width: usize, h: f32) -> Map {
+    let source = dir.path().join(fname);
+    fs::write(&source, b"source").unwrap();
+    RasterCache::new(&cache_dir(dir))
+        .write(fname, &source, width, width, &vec![h; width*width]).unwrap();
+
+    let m = flat_map(fname, nw, delta, width, h);
+    m.unload_image();
+    m
+}
@@ -0,0 +1,111 @@
+use crate::errors::*;
+
+use std::ffi::CString;
+use std::io::{self, Read, Seek, SeekFrom};
+
+const SEEK_SET: i32 = 0;
+const SEEK_END: i32 = 2;
+
+/*
+File opened through GDAL's virtual file system, so that the headers of maps
+within zip files (/vsizip/ paths) can be read the same way as those of
+plain files.
+ */
+pub struct VsiFile {
+    fp: *mut gdal_sys::VSILFILE,
+}
+
+impl VsiFile {
+    pub fn open(path: &str) -> Result<Self> {
+        let c_path = CString::new(path)?;
+        let fp = unsafe { gdal_sys::VSIFOpenL(c_path.as_ptr(), c"rb".as_ptr()) };
+
+        if fp.is_null() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("Unable to open {}", path))
+                       .into());
+        }
+
+        Ok(Self { fp })
+    }
+
+    fn tell(&self) -> u64 {
+        unsafe { gdal_sys::VSIFTellL(self.fp) }
+    }
+
+    fn seek_to(&mut self, offset: u64, whence: i32) -> io::Result<u64> {
+        if unsafe { gdal_sys::VSIFSeekL(self.fp, offset, whence) } != 0 {
+            return Err(io::Error::other("Seek failed"));
+        }
+
+        Ok(self.tell())
+    }
+}
+
+impl Read for VsiFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(unsafe {
+            gdal_sys::VSIFReadL(buf.as_mut_ptr().cast(), 1, buf.len(), self.fp)
+        })
+    }
+}
+
+impl Seek for VsiFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        // VSI offsets are unsigned, so relative seeks are made absolute
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => return self.seek_to(offset, SEEK_SET),
+            SeekFrom::End(offset) => (self.seek_to(0, SEEK_END)?, offset),
+            SeekFrom::Current(offset) => (self.tell(), offset),
+        };
+
+        let target = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput,
+                           "Seek before start of file")
+        })?;
+
+        self.seek_to(target, SEEK_SET)
+    }
+}
+
+impl Drop for VsiFile {
+    fn drop(&mut self) {
+        unsafe { gdal_sys::VSIFCloseL(self.fp); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vsifile::VsiFile;
+    use std::fs;
+    use std::io::{Read, Seek, SeekFrom};
+
+    #[test]
+    fn read_and_seek() {
+        let expected = fs::read("testdata/plane.tif").unwrap();
+        let n = expected.len();
+
+        // The same bytes are read from the plain file and from the zip file
+        for path in ["testdata/plane.tif",
+                     "/vsizip/testdata/plane.zip/plane.tif"] {
+            let mut f = VsiFile::open(path).unwrap();
+            let mut data = Vec::new();
+            f.read_to_end(&mut data).unwrap();
+            assert_eq!(data, expected);
+
+            let mut buf = [0; 4];
+            assert_eq!(f.seek(SeekFrom::End(-4)).unwrap(), n as u64 - 4);
+            f.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, expected[n - 4..]);
+
+            assert_eq!(f.seek(SeekFrom::Start(2)).unwrap(), 2);
+            assert_eq!(f.seek(SeekFrom::Current(2)).unwrap(), 4);
+            f.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, expected[4..8]);
+            assert!(f.seek(SeekFrom::Current(-100)).is_err());
+        }
+
+        assert!(VsiFile::open("/vsizip/testdata/plane.zip/missing.tif")
+                .is_err());
+    }
+}